@@ -1,47 +1,21 @@
 extern crate futures;
 extern crate tokio_core;
 extern crate tokio_interceptor;
+extern crate tokio_interceptor_macros;
 
 
-use std::{io, thread};
-use std::io::BufRead;
-
-use futures::stream::iter_result;
-use futures::{future, Future, Sink, Stream};
-use futures::sync::mpsc::{unbounded, SendError, UnboundedReceiver};
+use futures::Future;
 use tokio_core::reactor::Core;
-use tokio_interceptor::{App, Context, Db, Dispatcher, Effect,
-                        Event, EventInterceptor, Interceptor};
-
-#[derive(Debug)]
-enum Error {
-    Stdin(std::io::Error),
-    Channel(SendError<String>),
-}
-
-/// Spawn a new thread that reads from stdin and passes messages back using an unbounded channel.
-pub fn spawn_stdin_stream_unbounded() -> UnboundedReceiver<String> {
-    let (channel_sink, channel_stream) = unbounded();
-    let stdin_sink = channel_sink.sink_map_err(Error::Channel);
-
-    thread::spawn(move || {
-        let stdin = io::stdin();
-        let stdin_lock = stdin.lock();
-        iter_result(stdin_lock.lines())
-            .map_err(Error::Stdin)
-            .forward(stdin_sink)
-            .wait()
-            .unwrap();
-    });
-
-    channel_stream
-}
+use tokio_interceptor::{declare, stdin_lines, App, Context, Db, Dispatcher, Effect, Effects,
+                        Event, EventInterceptor, Interceptor, RegistrationError};
+use tokio_interceptor_macros::event;
 
 #[derive(Copy, Clone, Debug)]
 enum Mode {
     Adding, Removing, Marking, Menu, Quitting,
 }
 
+#[derive(Clone)]
 struct AppState {
     mode: Mode,
     todos: Vec<(bool, String)>,
@@ -62,6 +36,10 @@ impl Effect for Print {
     fn action(self: Box<Self>) {
         println!("{}", self.0);
     }
+
+    fn describe(&self) -> String {
+        format!("print {:?}", self.0)
+    }
 }
 
 struct ShowMenu;
@@ -226,67 +204,35 @@ impl Event<()> for ShowTodos {
     }
 }
 
-struct AddTodo;
-
-impl Event<()> for AddTodo {
-    fn handle(self: Box<Self>, mut context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
-        {
-            let input = context.coeffects.remove::<NonEmptyInput>().unwrap().0;
-            let db = context.coeffects.get::<Db<AppState>>().unwrap();
-            context.effects.push(Box::new(db.mutate(move |state: &mut AppState| state.todos.push((false, input)))));
-        }
-        context.next()
-    }
+#[event]
+fn add_todo(db: &Db<AppState>, input: NonEmptyInput) -> Effects {
+    vec![Box::new(db.mutate(move |state: &mut AppState| state.todos.push((false, input.0))))]
 }
 
-struct RemoveTodo;
-
 #[derive(Debug)]
 enum RemoveError<E> {
     ParseError(E),
     OutOfRange(isize),
 }
 
-impl Event<()> for RemoveTodo {
-    fn handle(self: Box<Self>, mut context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
-        {
-            let index_res = context.coeffects.remove::<Index>().unwrap().0;
-            let db = context.coeffects.get::<Db<AppState>>().unwrap();
-            match index_res {
-                Ok(index) => {
-                    context.effects.push(Box::new(db.mutate(move |state: &mut AppState| {
-                        state.todos.remove(index);
-                    })));
-                },
-                Err(e) => {
-                    context.effects.push(Box::new(Print(format!("Error removing: {:?}", e))))
-                },
-            };
-        }
-        context.next()
+#[event]
+fn remove_todo(db: &Db<AppState>, index: Index) -> Effects {
+    match index.0 {
+        Ok(index) => vec![Box::new(db.mutate(move |state: &mut AppState| {
+            state.todos.remove(index);
+        }))],
+        Err(e) => vec![Box::new(Print(format!("Error removing: {:?}", e)))],
     }
 }
 
-struct ToggleMark;
-
-impl Event<()> for ToggleMark {
-    fn handle(self: Box<Self>, mut context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
-        {
-            let index_res = context.coeffects.remove::<Index>().unwrap().0;
-            let db = context.coeffects.get::<Db<AppState>>().unwrap();
-            match index_res {
-                Ok(index) => {
-                    context.effects.push(Box::new(db.mutate(move |state: &mut AppState| {
-                        let todo = state.todos.get_mut(index).unwrap();
-                        todo.0 = ! todo.0;
-                    })));
-                },
-                Err(e) => {
-                    context.effects.push(Box::new(Print(format!("Error marking: {:?}", e))))
-                },
-            };
-        }
-        context.next()
+#[event]
+fn toggle_mark(db: &Db<AppState>, index: Index) -> Effects {
+    match index.0 {
+        Ok(index) => vec![Box::new(db.mutate(move |state: &mut AppState| {
+            let todo = state.todos.get_mut(index).unwrap();
+            todo.0 = ! todo.0;
+        }))],
+        Err(e) => vec![Box::new(Print(format!("Error marking: {:?}", e)))],
     }
 }
 
@@ -323,25 +269,25 @@ impl Interceptor for ShowPrompt {
     fn after(&self, mut context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
         {
             let dispatcher = context.coeffects.get::<Dispatcher<()>>().unwrap();
-            context.effects.push(dispatcher.dispatch(ShowPrompt));
+            context.push_effect(declare(dispatcher.dispatch(ShowPrompt)).dedup("show-prompt"));
         }
         context.next()
     }
 }
 
-struct Quit(i64);
+struct Quit(i32);
 
-impl Event<()> for Quit {
-    fn handle(self: Box<Self>, _context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
-        Box::new(future::err(()))
-    }
+#[event(Quit)]
+fn quit(event: Quit, dispatcher: &Dispatcher<()>) -> Effects {
+    vec![dispatcher.shutdown(event.0)]
 }
 
-fn setup(app: &mut App<AppState>) {
-    app.register_event::<ShowPrompt>();
-    app.register_event::<ShowMenu>();
-    app.register_event::<ShowTodos>();
-    app.register_event_with::<Input>(vec![Box::new(ShowPrompt)]);
+fn setup(app: &App<AppState>) -> Result<(), RegistrationError> {
+    app.register_event::<ShowPrompt>()?;
+    app.register_event::<ShowMenu>()?;
+    app.register_event::<ShowTodos>()?;
+    app.register_event_with::<Input>(vec![Box::new(ShowPrompt)])?;
+    Ok(())
 }
 
 pub fn main() -> Result<(), ()> {
@@ -349,16 +295,14 @@ pub fn main() -> Result<(), ()> {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    let mut app = App::new(handle);
-    setup(&mut app);
+    let app = App::new(handle);
+    setup(&app).expect("failed to register events");
 
     let handle = core.handle();
     handle.spawn(app.dispatch(ShowMenu).map(|_| ()).map_err(|_| ()));
 
-    let std_in_ch = spawn_stdin_stream_unbounded();
-    core.run(std_in_ch.for_each(|m| {
-        app.dispatch(Input(m)).map(|_| ()).map_err(|_| ())
-    }))?;
+    app.attach_source(stdin_lines(), Input);
+    let exit_code = core.run(app.run())?;
 
-    Ok(())
+    std::process::exit(exit_code)
 }
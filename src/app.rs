@@ -13,54 +13,754 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::cell::RefCell;
+use std::any::{Any,TypeId,type_name};
+use std::collections::{BTreeMap,HashMap};
+use std::fmt::Debug;
+#[cfg(any(feature = "metrics", feature = "debug-server"))]
+use std::io;
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+#[cfg(feature = "debug-server")]
+use std::path::Path;
 use std::rc::Rc;
+use std::time::Duration;
 
-use futures::Future;
+use futures::{Future, Stream};
 use tokio_core::reactor::Handle;
 
-use super::{Db, Dispatcher, Event, EventDispatcher,
-            HandleEffects, InjectCoeffect, Interceptor};
+use super::{CancelHandle, CatchPanic, Context, Db, Dispatcher, ErrorPolicy, Event, EventDispatcher,
+            EventMetrics, HandleEffects, InjectCoeffect, InjectDispatcher, Interceptor, IntoContextFuture, LagPolicy, Lifecycle,
+            Metrics, NewCoeffect,
+            Registration, RegistrationError, Run, SourceHandle, Subscription, Timeout};
+use source::Attached;
+use stores::{Access,Store,StoreGuard,StoreId,StoreLocks};
+#[cfg(feature = "debug-server")]
+use debug::Debugger;
+
+type Factory = Rc<Fn() -> Box<Interceptor<Error = ()>>>;
+
+/// The interceptors an event was registered with.
+type Chain = Vec<Rc<Box<Interceptor<Error = ()>>>>;
+
+/// An interceptor added with `AppBuilder::effect_handler`, kept from
+/// performing effects in a dry run.
+struct EffectHandler(Box<Interceptor<Error = ()>>);
+
+impl Interceptor for EffectHandler {
+    type Error = ();
+
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn before(&self, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+        self.0.before(context)
+    }
+
+    fn after(&self, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+        if context.dry_run().is_some() {
+            return context.next();
+        }
+        self.0.after(context)
+    }
+
+    fn error(&self, error: &()) {
+        self.0.error(error)
+    }
+}
+
+const DEFAULT_SUBSCRIPTION_CAPACITY: usize = 64;
+
+/// One entry in the stack of interceptors an `App` puts in front of
+/// every event it registers.
+#[derive(Clone)]
+pub enum Layer {
+    /// Injects the app's `Db` and its other stores as coeffects. For
+    /// an event with declared access, only the stores it declared are
+    /// injected, after checking them for conflicts.
+    Db,
+    /// Injects a `Dispatcher` for the app as a coeffect, with its
+    /// dispatches recorded as caused by the current event.
+    Dispatcher,
+    /// Injects the coeffects added with `AppBuilder::coeffect`.
+    Coeffects,
+    /// Performs the effects left in the context.
+    HandleEffects,
+    /// The interceptors added with `AppBuilder::effect_handler`. Placed
+    /// after `HandleEffects`, their `after` sees the effects before
+    /// they are performed. It is skipped in a dry run, leaving the
+    /// effects to be described.
+    EffectHandlers,
+    /// Any other interceptor, constructed afresh for each event.
+    Custom(Factory),
+}
+
+impl Layer {
+    pub fn custom<F>(factory: F) -> Layer
+    where F: 'static + Fn() -> Box<Interceptor<Error = ()>>,
+    {
+        Layer::Custom(Rc::new(factory))
+    }
+
+    /// The stack used by `App::new`.
+    pub fn defaults() -> Vec<Layer> {
+        vec![Layer::Db, Layer::Dispatcher, Layer::Coeffects,
+             Layer::HandleEffects, Layer::EffectHandlers]
+    }
+}
+
+/// Configures and builds an `App`.
+pub struct AppBuilder<State> {
+    handle: Handle,
+    state: Option<State>,
+    stores: Vec<Box<Store>>,
+    access: HashMap<TypeId, Access>,
+    stack: Vec<Layer>,
+    coeffects: Vec<Factory>,
+    effect_handlers: Vec<Factory>,
+    catch_panics: bool,
+    transactional: bool,
+    timeout: Option<Duration>,
+    metrics: Option<Metrics>,
+    #[cfg(feature = "debug-server")]
+    debugger: Option<Debugger<State>>,
+}
+
+impl<State> AppBuilder<State>
+where State: 'static + Clone + Default,
+{
+    pub fn new(handle: Handle) -> AppBuilder<State> {
+        AppBuilder {
+            handle,
+            state: None,
+            stores: vec![],
+            access: HashMap::new(),
+            stack: Layer::defaults(),
+            coeffects: vec![],
+            effect_handlers: vec![],
+            catch_panics: false,
+            transactional: false,
+            timeout: None,
+            metrics: None,
+            #[cfg(feature = "debug-server")]
+            debugger: None,
+        }
+    }
+
+    /// The initial value of the app's `Db`. Defaults to
+    /// `State::default()`.
+    pub fn state(mut self, state: State) -> AppBuilder<State> {
+        self.state = Some(state);
+        self
+    }
+
+    /// Add a `Db` holding `state`, injected alongside the app's own
+    /// into every event that does not declare its access. There is one
+    /// store per state type: a later store of the same type replaces
+    /// an earlier one, and one of type `State` sets the app's initial
+    /// state.
+    pub fn store<S: 'static + Clone>(mut self, state: S) -> AppBuilder<State> {
+        if TypeId::of::<S>() == TypeId::of::<State>() {
+            let mut state = Some(state);
+            self.state = (&mut state as &mut Any).downcast_mut::<Option<State>>().and_then(Option::take);
+        } else {
+            self.stores.retain(|store| store.id() != StoreId::of::<S>());
+            self.stores.push(Box::new(Db::new(state)));
+        }
+        self
+    }
+
+    /// Declare the stores that `Ev` reads and mutates; only those are
+    /// injected into its dispatches. A dispatch of `Ev` fails with
+    /// `DispatchError::Conflict` while another one in flight mutates a
+    /// store it reads, or touches one it mutates.
+    pub fn access<Ev: 'static>(mut self, access: Access) -> AppBuilder<State> {
+        self.access.insert(TypeId::of::<Ev>(), access);
+        self
+    }
+
+    /// Replace the default interceptor stack. Leaving out a layer
+    /// removes it, and the order given is the order the interceptors
+    /// run in.
+    pub fn stack(mut self, stack: Vec<Layer>) -> AppBuilder<State> {
+        self.stack = stack;
+        self
+    }
+
+    /// Inject `new_coeffect` into every event, at the position of
+    /// `Layer::Coeffects` in the stack.
+    pub fn coeffect<C>(mut self, new_coeffect: C) -> AppBuilder<State>
+    where C: 'static + Clone + NewCoeffect,
+    {
+        self.coeffects.push(Rc::new(move || {
+            Box::new(InjectCoeffect::<C, ()>::new(new_coeffect.clone())) as Box<Interceptor<Error = ()>>
+        }));
+        self
+    }
+
+    /// Add an interceptor at the position of `Layer::EffectHandlers` in
+    /// the stack.
+    pub fn effect_handler<F>(mut self, factory: F) -> AppBuilder<State>
+    where F: 'static + Fn() -> Box<Interceptor<Error = ()>>,
+    {
+        self.effect_handlers.push(Rc::new(factory));
+        self
+    }
+
+    /// Convert panics in handlers and effects into failed dispatches.
+    /// Puts a `CatchPanic` in front of the stack and makes
+    /// `HandleEffects` panic-safe.
+    pub fn catch_panics(mut self, catch_panics: bool) -> AppBuilder<State> {
+        self.catch_panics = catch_panics;
+        self
+    }
+
+    /// Perform the effects of each dispatch all or nothing: if one of
+    /// them panics, the app's `Db` is restored and the others are
+    /// compensated. See `HandleEffects::transactional`.
+    pub fn transactional_effects(mut self, transactional: bool) -> AppBuilder<State> {
+        self.transactional = transactional;
+        self
+    }
+
+    /// Abort any dispatch that takes longer than `duration`.
+    pub fn timeout(mut self, duration: Duration) -> AppBuilder<State> {
+        self.timeout = Some(duration);
+        self
+    }
+
+    /// Collect dispatch metrics into `metrics`, with its interceptor
+    /// in front of the stack. See `App::metrics`.
+    pub fn metrics(mut self, metrics: &Metrics) -> AppBuilder<State> {
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    /// Record every dispatch with `debugger`, in front of the rest of
+    /// the stack. See `App::serve_debugger`.
+    #[cfg(feature = "debug-server")]
+    pub fn debugger(mut self, debugger: &Debugger<State>) -> AppBuilder<State> {
+        self.debugger = Some(debugger.clone());
+        self
+    }
+
+    pub fn build(self) -> App<State> {
+        let dispatcher = EventDispatcher::new();
+        #[cfg(feature = "debug-server")]
+        {
+            if let Some(ref debugger) = self.debugger {
+                debugger.attach(&dispatcher);
+            }
+        }
+        App {
+            db: Db::new(self.state.unwrap_or_default()),
+            stores: self.stores,
+            access: self.access,
+            locks: StoreLocks::new(),
+            handle: self.handle,
+            lifecycle: dispatcher.lifecycle().clone(),
+            dispatcher,
+            stack: self.stack,
+            coeffects: self.coeffects,
+            effect_handlers: self.effect_handlers,
+            catch_panics: self.catch_panics,
+            transactional: self.transactional,
+            timeout: self.timeout,
+            metrics: self.metrics,
+            #[cfg(feature = "debug-server")]
+            debugger: self.debugger,
+        }
+    }
+}
 
 pub struct App<State> {
     handle: Handle,
     db: Db<State>,
-    dispatcher: Rc<RefCell<EventDispatcher<()>>>,
+    /// The stores other than `db`.
+    stores: Vec<Box<Store>>,
+    access: HashMap<TypeId, Access>,
+    locks: StoreLocks,
+    dispatcher: EventDispatcher<()>,
+    lifecycle: Lifecycle,
+    stack: Vec<Layer>,
+    coeffects: Vec<Factory>,
+    effect_handlers: Vec<Factory>,
+    catch_panics: bool,
+    transactional: bool,
+    timeout: Option<Duration>,
+    metrics: Option<Metrics>,
+    #[cfg(feature = "debug-server")]
+    debugger: Option<Debugger<State>>,
 }
 
 impl<State> App<State>
 where State: 'static + Clone + Default,
 {
     pub fn new(handle: Handle) -> App<State> {
-        App { handle,
-              db: Db::new(State::default()),
-              dispatcher: Rc::new(RefCell::new(EventDispatcher::new())) }
+        AppBuilder::new(handle).build()
+    }
+
+    pub fn builder(handle: Handle) -> AppBuilder<State> {
+        AppBuilder::new(handle)
+    }
+
+    /// The store holding state of type `S`, if the app has one.
+    pub fn store<S: 'static>(&self) -> Option<Db<S>> {
+        let db: &Any = &self.db;
+        db.downcast_ref::<Db<S>>()
+            .or_else(|| self.stores.iter().filter_map(|store| store.as_any().downcast_ref::<Db<S>>()).next())
+            .cloned()
     }
 
     pub fn default_interceptors(&self) -> Vec<Box<Interceptor<Error = ()>>> {
-        let inject_state = InjectCoeffect::<Db<State>, ()>::new(self.db.clone());
-        let inject_dispatcher = InjectCoeffect::<Dispatcher<()>, ()>::new(Dispatcher::new(&self.handle, &self.dispatcher));
-        let handle_effects = HandleEffects::new();
-        vec![Box::new(inject_state), Box::new(inject_dispatcher), Box::new(handle_effects)]
+        self.interceptors(None)
+    }
+
+    /// The default interceptors for `Ev`, guarding the stores it
+    /// declared access to.
+    fn interceptors_for<Ev: 'static>(&self) -> Result<Vec<Box<Interceptor<Error = ()>>>, RegistrationError> {
+        let access = self.access.get(&TypeId::of::<Ev>());
+        if let Some(access) = access {
+            let unknown = access.stores().into_iter()
+                .find(|&id| id != StoreId::of::<State>() && self.stores.iter().all(|store| store.id() != id));
+            if let Some(store) = unknown {
+                return Err(RegistrationError::UnknownStore { event: type_name::<Ev>(), store: store.name() });
+            }
+        }
+        Ok(self.interceptors(access))
+    }
+
+    fn interceptors(&self, access: Option<&Access>) -> Vec<Box<Interceptor<Error = ()>>> {
+        let mut interceptors: Vec<Box<Interceptor<Error = ()>>> = vec![];
+        #[cfg(feature = "debug-server")]
+        {
+            if let Some(ref debugger) = self.debugger {
+                interceptors.push(Box::new(debugger.interceptor(&self.db)));
+            }
+        }
+        if let Some(ref metrics) = self.metrics {
+            interceptors.push(Box::new(metrics.interceptor()));
+        }
+        if self.catch_panics {
+            interceptors.push(Box::new(CatchPanic::new()));
+        }
+        if let Some(duration) = self.timeout {
+            interceptors.push(Box::new(Timeout::new(&self.handle, duration)));
+        }
+        for layer in self.stack.iter() {
+            match *layer {
+                Layer::Db => {
+                    let declared = access.map(Access::stores);
+                    let injected = |id: StoreId| declared.as_ref().is_none_or(|declared| declared.contains(&id));
+                    if let Some(access) = access {
+                        interceptors.push(Box::new(StoreGuard::new(&self.locks, access.clone())));
+                    }
+                    if injected(StoreId::of::<State>()) {
+                        interceptors.push(Box::new(InjectCoeffect::<Db<State>, ()>::new(self.db.clone())));
+                    }
+                    interceptors.extend(self.stores.iter()
+                                        .filter(|store| injected(store.id()))
+                                        .map(|store| store.inject()));
+                },
+                Layer::Dispatcher => {
+                    let dispatcher = Dispatcher::with_lifecycle(&self.handle, &self.dispatcher, &self.lifecycle);
+                    interceptors.push(Box::new(InjectDispatcher::new(dispatcher)));
+                },
+                Layer::Coeffects => interceptors.extend(self.coeffects.iter().map(|f| f())),
+                Layer::HandleEffects => {
+                    let handle_effects: HandleEffects<()> = if self.transactional {
+                        let staged = HandleEffects::transactional().stage(&self.db);
+                        self.stores.iter().fold(staged, |staged, store| store.stage(staged))
+                    } else if self.catch_panics {
+                        HandleEffects::panic_safe()
+                    } else {
+                        HandleEffects::new()
+                    };
+                    interceptors.push(Box::new(handle_effects));
+                },
+                Layer::EffectHandlers => {
+                    interceptors.extend(self.effect_handlers.iter()
+                                        .map(|f| Box::new(EffectHandler(f())) as Box<Interceptor<Error = ()>>));
+                },
+                Layer::Custom(ref factory) => interceptors.push(factory()),
+            }
+        }
+        interceptors
+    }
+
+    pub fn register_event<E: 'static + Event<()>>(&self) -> Result<(), RegistrationError> {
+        self.register_event_with::<E>(vec![])
+    }
+
+    pub fn register_event_with<E: 'static + Event<()>>(&self, mut interceptors: Vec<Box<Interceptor<Error = ()>>>)
+                                                       -> Result<(), RegistrationError> {
+        let mut i = self.interceptors_for::<E>()?;
+        i.append(&mut interceptors);
+        self.dispatcher.register_event::<E>(i)
     }
 
-    pub fn register_event<E: 'static + Event<()>>(&mut self) {
-        self.register_event_with::<E>(vec![]);
+    /// Register `f` as the handler for `E`, after the default
+    /// interceptors. `E` needs no `Event` impl; `f` may return the
+    /// `Context` or a future of it. `E` can be named as
+    /// `register_fn::<E, _>` when the closure does not pin it down.
+    pub fn register_fn<E, R>(&self, f: impl 'static + Fn(E, Context<()>) -> R) -> Result<(), RegistrationError>
+    where E: 'static,
+          R: IntoContextFuture<()>,
+    {
+        self.dispatcher.register_fn(self.interceptors_for::<E>()?, f)
     }
 
-    pub fn register_event_with<E: 'static + Event<()>>(&mut self, mut interceptors: Vec<Box<Interceptor<Error = ()>>>) {
-        let mut i = self.default_interceptors();
+    /// Like `register_event_with`, but `E` is unregistered when the
+    /// returned `Registration` is dropped.
+    pub fn register_scoped_with<E: 'static + Event<()>>(&self, mut interceptors: Vec<Box<Interceptor<Error = ()>>>)
+                                                        -> Result<Registration<()>, RegistrationError> {
+        let mut i = self.interceptors_for::<E>()?;
         i.append(&mut interceptors);
+        self.dispatcher.register_scoped::<E>(i)
+    }
+
+    /// Remove `E`'s handler, returning its chain if it was registered.
+    pub fn unregister_event<E: 'static>(&self) -> Option<Chain> {
+        self.dispatcher.unregister_event::<E>()
+    }
+
+    /// Register `E` with the default interceptors followed by
+    /// `interceptors`, replacing any existing handler. Returns the
+    /// chain it replaced.
+    pub fn replace_event_with<E: 'static + Event<()>>(&self, mut interceptors: Vec<Box<Interceptor<Error = ()>>>)
+                                                      -> Result<Option<Chain>, RegistrationError> {
+        let mut i = self.interceptors_for::<E>()?;
+        i.append(&mut interceptors);
+        Ok(self.dispatcher.replace_event::<E>(i))
+    }
+
+    pub fn dispatch<E: 'static>(&self, e: E) -> impl Future {
+        self.dispatcher.dispatch(e)
+    }
 
-        match self.dispatcher.try_borrow_mut() {
-            Ok(mut dispatcher) => dispatcher.register_event::<E>(i),
-            Err(e) => {
-                warn!("failed to register event: did not have unique access to EventDispatcher: {}", e);
-            },
-        };
+    /// The metrics collected so far by the `Metrics` installed with
+    /// `AppBuilder::metrics`, keyed by event type.
+    pub fn metrics(&self) -> Option<BTreeMap<&'static str, EventMetrics>> {
+        self.metrics.as_ref().map(Metrics::snapshot)
     }
 
-    pub fn dispatch<E: 'static + Event<()>>(&self, e: E) -> impl Future {
-        self.dispatcher.borrow().dispatch(e)
+    /// Serve the metrics installed with `AppBuilder::metrics` in the
+    /// Prometheus text format over HTTP at `addr`, returning the
+    /// address bound.
+    #[cfg(feature = "metrics")]
+    pub fn serve_metrics(&self, addr: &SocketAddr) -> io::Result<SocketAddr> {
+        match self.metrics {
+            Some(ref metrics) => metrics.serve(addr, &self.handle),
+            None => Err(io::Error::other("the app has no metrics installed")),
+        }
+    }
+
+    /// Serve the debugger installed with `AppBuilder::debugger` on the
+    /// Unix domain socket at `path`. See `Debugger` for the protocol.
+    #[cfg(feature = "debug-server")]
+    pub fn serve_debugger<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        match self.debugger {
+            Some(ref debugger) => debugger.serve(path, &self.handle, &self.db),
+            None => Err(io::Error::other("the app has no debugger installed")),
+        }
+    }
+
+    /// Dispatch `e` without performing its effects. See
+    /// `EventDispatcher::dispatch_dry_run`.
+    pub fn dispatch_dry_run<E: 'static>(&self, e: E) -> impl Future<Item = Context<()>, Error = ()> {
+        self.dispatcher.dispatch_dry_run(e)
+    }
+
+    pub fn dispatch_cancellable<E: 'static>(&self, e: E) -> (impl Future, CancelHandle) {
+        self.dispatcher.dispatch_cancellable(e)
+    }
+
+    /// A stream of an `Observation` of every completed dispatch of `E`,
+    /// which for a failed dispatch carries the `DispatchError` that the
+    /// app's `()` error type cannot. Buffers up to 64 observations,
+    /// dropping the oldest when the consumer falls behind.
+    pub fn subscribe_events<E: 'static>(&self) -> Subscription {
+        self.subscribe_events_with::<E>(DEFAULT_SUBSCRIPTION_CAPACITY, LagPolicy::DropOldest)
+    }
+
+    pub fn subscribe_events_with<E: 'static>(&self, capacity: usize, policy: LagPolicy) -> Subscription {
+        self.dispatcher.subscribe::<E>(capacity, policy)
+    }
+
+    /// Like `subscribe_events`, for every event.
+    pub fn subscribe_all(&self) -> Subscription {
+        self.subscribe_all_with(DEFAULT_SUBSCRIPTION_CAPACITY, LagPolicy::DropOldest)
+    }
+
+    pub fn subscribe_all_with(&self, capacity: usize, policy: LagPolicy) -> Subscription {
+        self.dispatcher.subscribe_all(capacity, policy)
+    }
+
+    /// Run the app until a `Shutdown` effect is performed, resolving
+    /// with its exit code.
+    ///
+    /// `AppStarted` is dispatched when the returned future is first
+    /// polled. On shutdown, attached sources are detached and
+    /// `AppStopping` is dispatched; either is skipped if unregistered.
+    /// The future then resolves once every dispatch started through a
+    /// `Dispatcher` or a source, including those started while
+    /// draining, has completed.
+    pub fn run(&self) -> Run<()> {
+        Run::new(&self.handle, &self.lifecycle, &self.dispatcher)
+    }
+
+    /// Dispatch an event for every item of `source`, as produced by
+    /// `mapper`. Errors from the stream or from the dispatches are
+    /// logged and skipped.
+    pub fn attach_source<S, F, Ev>(&self, source: S, mapper: F) -> SourceHandle
+    where S: 'static + Stream,
+          S::Error: Debug,
+          F: 'static + FnMut(S::Item) -> Ev,
+          Ev: 'static,
+    {
+        self.attach_source_with(source, mapper, ErrorPolicy::Skip)
+    }
+
+    /// Like `attach_source`, with `policy` deciding whether errors
+    /// detach the source. The source is also detached when the stream
+    /// ends.
+    pub fn attach_source_with<S, F, Ev>(&self, source: S, mapper: F, policy: ErrorPolicy) -> SourceHandle
+    where S: 'static + Stream,
+          S::Error: Debug,
+          F: 'static + FnMut(S::Item) -> Ev,
+          Ev: 'static,
+    {
+        let attached = Attached::new(source, mapper, policy, &self.dispatcher, &self.lifecycle);
+        let handle = attached.handle();
+        self.handle.spawn(attached);
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::{Cell,RefCell};
+
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    use {Coeffect,DispatchError,Outcome};
+
+    #[derive(Clone,Default)]
+    struct Count(u32);
+
+    #[derive(Clone)]
+    struct Greeting(&'static str);
+
+    impl Coeffect for Greeting {}
+
+    impl NewCoeffect for Greeting {
+        type Instance = Greeting;
+
+        fn new_coeffect(&self) -> Greeting {
+            self.clone()
+        }
+    }
+
+    struct Inspect(Rc<Cell<(u32, bool, bool)>>);
+
+    impl Event<()> for Inspect {
+        fn handle(self: Box<Self>, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            let count = context.coeffects.get::<Db<Count>>().map_or(0, |db| db.borrow().0);
+            let greeted = context.coeffects.get::<Greeting>().is_some_and(|g| g.0 == "hello");
+            let dispatcher = context.coeffects.contains::<Dispatcher<()>>();
+            self.0.set((count, greeted, dispatcher));
+            Box::new(future::ok(context))
+        }
+    }
+
+    #[test]
+    fn test_builder_sets_state_and_coeffects() {
+        let mut core = Core::new().unwrap();
+        let app = App::builder(core.handle())
+            .state(Count(5))
+            .coeffect(Greeting("hello"))
+            .build();
+        app.register_event::<Inspect>().unwrap();
+
+        let seen = Rc::new(Cell::new((0, false, false)));
+        core.run(app.dispatch(Inspect(Rc::clone(&seen)))).ok().unwrap();
+        assert_eq!((5, true, true), seen.get());
+    }
+
+    #[test]
+    fn test_builder_replaces_default_stack() {
+        let mut core = Core::new().unwrap();
+        let app: App<Count> = App::builder(core.handle())
+            .stack(vec![Layer::Db, Layer::HandleEffects])
+            .build();
+        app.register_event::<Inspect>().unwrap();
+
+        let seen = Rc::new(Cell::new((0, false, true)));
+        core.run(app.dispatch(Inspect(Rc::clone(&seen)))).ok().unwrap();
+        assert_eq!((0, false, false), seen.get());
+        assert_eq!(2, app.default_interceptors().len());
+    }
+
+    struct Increment(u32);
+
+    #[test]
+    fn test_register_fn() {
+        let mut core = Core::new().unwrap();
+        let app: App<Count> = App::new(core.handle());
+        app.register_fn(|Increment(n), mut context: Context<()>| {
+            let db = context.coeffects.remove::<Db<Count>>().unwrap();
+            context.push_effect(db.mutate(move |count: &mut Count| count.0 += n));
+            context
+        }).unwrap();
+
+        core.run(app.dispatch(Increment(3))).ok().unwrap();
+        core.run(app.dispatch(Increment(4))).ok().unwrap();
+        assert_eq!(7, app.db.borrow().0);
+    }
+
+    #[test]
+    fn test_unregister_and_replace_return_the_previous_chain() {
+        let core = Core::new().unwrap();
+        let app: App<Count> = App::builder(core.handle())
+            .stack(vec![Layer::Db, Layer::HandleEffects])
+            .build();
+
+        assert!(app.replace_event_with::<Inspect>(vec![]).unwrap().is_none());
+        assert_eq!(2, app.replace_event_with::<Inspect>(vec![]).unwrap().unwrap().len());
+        assert_eq!(2, app.unregister_event::<Inspect>().unwrap().len());
+        assert!(app.unregister_event::<Inspect>().is_none());
+    }
+
+    #[test]
+    fn test_dry_run_describes_effects() {
+        let mut core = Core::new().unwrap();
+        let app: App<Count> = App::new(core.handle());
+        app.register_fn(|Increment(n), mut context: Context<()>| {
+            let db = context.coeffects.remove::<Db<Count>>().unwrap();
+            let dispatcher = context.coeffects.remove::<Dispatcher<()>>().unwrap();
+            context.effects.push(dispatcher.dispatch(Cause));
+            context.push_effect(db.mutate(move |count: &mut Count| count.0 += n));
+            context
+        }).unwrap();
+
+        let context = core.run(app.dispatch_dry_run(Increment(3))).ok().unwrap();
+        let described: Vec<&str> = context.dry_run().unwrap().iter().map(|d| d.as_str()).collect();
+        assert_eq!(vec!["mutate tokio_interceptor::app::tests::Count",
+                        "dispatch tokio_interceptor::app::tests::Cause"], described);
+        assert_eq!(0, app.db.borrow().0);
+    }
+
+    struct Handled(Rc<Cell<u32>>);
+
+    impl Interceptor for Handled {
+        type Error = ();
+
+        fn after(&self, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            self.0.set(self.0.get() + context.effects.len() as u32);
+            Box::new(future::ok(context))
+        }
+    }
+
+    #[test]
+    fn test_dry_run_skips_effect_handlers() {
+        let mut core = Core::new().unwrap();
+        let handled = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&handled);
+        let app: App<Count> = App::builder(core.handle())
+            .effect_handler(move || Box::new(Handled(Rc::clone(&counter))))
+            .build();
+        app.register_fn(|Increment(n), mut context: Context<()>| {
+            let db = context.coeffects.remove::<Db<Count>>().unwrap();
+            context.push_effect(db.mutate(move |count: &mut Count| count.0 += n));
+            context
+        }).unwrap();
+
+        let context = core.run(app.dispatch_dry_run(Increment(3))).ok().unwrap();
+        assert_eq!(1, context.dry_run().unwrap().len());
+        assert_eq!(0, handled.get());
+
+        core.run(app.dispatch(Increment(3))).ok().unwrap();
+        assert_eq!(1, handled.get());
+        assert_eq!(3, app.db.borrow().0);
+    }
+
+    struct Cause;
+    struct Consequence;
+
+    #[test]
+    fn test_dispatches_from_handlers_record_their_cause() {
+        let mut core = Core::new().unwrap();
+        let app: App<Count> = App::new(core.handle());
+        let seen = Rc::new(RefCell::new(vec![]));
+
+        let cause_seen = Rc::clone(&seen);
+        app.register_fn(move |Cause, mut context: Context<()>| {
+            cause_seen.borrow_mut().push(context.meta().unwrap().clone());
+            let effect = context.coeffects.get::<Dispatcher<()>>().unwrap().dispatch(Consequence);
+            context.effects.push(effect);
+            context
+        }).unwrap();
+        let effect_seen = Rc::clone(&seen);
+        app.register_fn(move |Consequence, context: Context<()>| {
+            effect_seen.borrow_mut().push(context.meta().unwrap().clone());
+            context
+        }).unwrap();
+
+        core.run(app.dispatch(Cause)).ok().unwrap();
+        core.turn(Some(::std::time::Duration::from_millis(10)));
+
+        let seen = seen.borrow();
+        assert_eq!(2, seen.len());
+        assert!(seen[0].is_root());
+        assert_eq!(Some(seen[0].id), seen[1].parent);
+        assert_eq!(seen[0].root, seen[1].root);
+        assert!(seen[1].event.ends_with("Consequence"));
+    }
+
+    #[test]
+    fn test_subscribers_see_completed_dispatches() {
+        let mut core = Core::new().unwrap();
+        let app: App<Count> = App::new(core.handle());
+        app.register_fn(|Increment(n), mut context: Context<()>| {
+            let db = context.coeffects.remove::<Db<Count>>().unwrap();
+            context.push_effect(db.mutate(move |count: &mut Count| count.0 += n));
+            context
+        }).unwrap();
+        let increments = app.subscribe_events::<Increment>();
+        let all = app.subscribe_all();
+
+        core.run(app.dispatch(Increment(1))).ok().unwrap();
+        assert!(core.run(app.dispatch(Cause)).is_err());
+
+        let (observed, _) = core.run(increments.into_future()).ok().unwrap();
+        let observed = observed.unwrap();
+        assert!(observed.meta.event.ends_with("Increment"));
+        assert_eq!(Outcome::Ok, observed.outcome);
+        let observed = core.run(all.take(2).collect()).unwrap();
+        assert!(observed[1].meta.event.ends_with("Cause"));
+        assert_eq!(Some(DispatchError::Unregistered { event: type_name::<Cause>() }), observed[1].error);
+    }
+
+    struct Explode;
+
+    #[test]
+    fn test_subscribers_see_why_a_dispatch_failed() {
+        let mut core = Core::new().unwrap();
+        let app: App<Count> = App::builder(core.handle()).catch_panics(true).build();
+        app.register_fn(|Explode, _context: Context<()>| -> Context<()> { panic!("kaboom") }).unwrap();
+        let failures = app.subscribe_events::<Explode>();
+
+        assert!(core.run(app.dispatch(Explode)).is_err());
+
+        let (observed, _) = core.run(failures.into_future()).ok().unwrap();
+        let observed = observed.unwrap();
+        assert_eq!(Outcome::Err, observed.outcome);
+        match observed.error {
+            Some(DispatchError::Panicked { ref message, .. }) => assert_eq!("kaboom", message),
+            ref other => panic!("expected a panic to be observed, got {:?}", other),
+        }
     }
 }
@@ -0,0 +1,182 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::any::type_name;
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+
+use futures::{future,Future};
+
+use super::{Context,Dispatched,EventMeta,Interceptor,InterceptorQueue};
+use closure::IntoContextFuture;
+use instrument::DispatchSpan;
+
+/// An interceptor that wraps the rest of the chain in a single call,
+/// so that it can keep local state, such as a timer or a guard, across
+/// it.
+///
+/// `around` is given the context and a `Next` which, when run, takes
+/// the context through the rest of the chain — every remaining
+/// `before`, the event, and the `after` of everything that came later
+/// — and resolves with the context as it comes back. Not running
+/// `Next` skips the rest of the chain.
+///
+/// Closures taking `(Context<E>, Next<E>)` are `Around` interceptors
+/// too. Wrap either in `around` to put it in a chain.
+pub trait Around<E> {
+    fn around(&self, context: Context<E>, next: Next<E>) -> Box<Future<Item = Context<E>, Error = E>>;
+}
+
+impl<E, F, R> Around<E> for F
+where F: Fn(Context<E>, Next<E>) -> R,
+      R: IntoContextFuture<E>,
+{
+    fn around(&self, context: Context<E>, next: Next<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        self(context, next).into_context_future()
+    }
+}
+
+pub fn around<A, E>(around: A) -> AroundInterceptor<A, E>
+where A: Around<E>,
+{
+    AroundInterceptor(around, PhantomData)
+}
+
+pub struct AroundInterceptor<A, E>(A, PhantomData<E>);
+
+impl<A, E> Interceptor for AroundInterceptor<A, E>
+where A: 'static + Around<E>,
+      E: 'static,
+{
+    type Error = E;
+
+    fn name(&self) -> &str {
+        type_name::<A>()
+    }
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        let rest = mem::take(&mut context.queue);
+        self.0.around(context, Next { rest })
+    }
+}
+
+/// The rest of the chain after an `Around` interceptor.
+pub struct Next<E> {
+    rest: InterceptorQueue<E>,
+}
+
+impl<E: 'static> Next<E> {
+    /// Run the rest of the chain, after anything `context` already has
+    /// queued.
+    ///
+    /// The rest runs as a dispatch of its own that inherits the
+    /// cancellation, panic handling and instrumentation of the
+    /// enclosing one. Its
+    /// failures are reported to the interceptors it entered, and then
+    /// to those of the enclosing dispatch once the error is returned
+    /// from `around`.
+    pub fn run(self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>>
+    where E: From<super::DispatchError>,
+    {
+        for interceptor in self.rest {
+            context.queue.push_back(interceptor);
+        }
+        let outer = mem::take(&mut context.stack);
+        let meta = context.meta.clone().unwrap_or_else(|| EventMeta::root("unknown"));
+        let cancel = context.cancel.take();
+        let catch_panics = context.catch_panics;
+        let failure = Rc::clone(&context.failure);
+        let span = context.span.take().unwrap_or_else(|| DispatchSpan::new(&meta));
+
+        let mut rest = Dispatched::with_span(&meta, span, Box::new(future::ok(context)));
+        rest.cancel = cancel;
+        rest.catch_panics = catch_panics;
+        rest.failure = failure;
+        Box::new(rest.map(move |mut context| {
+            context.stack = outer;
+            context
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use {around_fn,DispatchError,EventDispatcher};
+
+    struct Ping;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    fn record(log: &Log, name: &'static str) -> Box<Interceptor<Error = DispatchError>> {
+        let log = Rc::clone(log);
+        Box::new(around_fn(move |context: Context<DispatchError>, next: Next<DispatchError>| {
+            log.borrow_mut().push(format!("before {}", name));
+            let log = Rc::clone(&log);
+            next.run(context).map(move |context| {
+                log.borrow_mut().push(format!("after {}", name));
+                context
+            })
+        }))
+    }
+
+    fn timed(log: &Log) -> Box<Interceptor<Error = DispatchError>> {
+        let log = Rc::clone(log);
+        Box::new(around(move |context: Context<DispatchError>, next: Next<DispatchError>| {
+            let started = log.borrow().len();
+            log.borrow_mut().push("enter".to_string());
+            let log = Rc::clone(&log);
+            next.run(context).map(move |context| {
+                let inner = log.borrow().len() - started - 1;
+                log.borrow_mut().push(format!("leave after {}", inner));
+                context
+            })
+        }))
+    }
+
+    #[test]
+    fn test_around_wraps_rest_of_chain() {
+        let log: Log = Rc::new(RefCell::new(vec![]));
+        let dispatcher = EventDispatcher::new();
+        let handler_log = Rc::clone(&log);
+        dispatcher.register_fn(vec![record(&log, "outer"), timed(&log), record(&log, "inner")],
+                               move |Ping, context: Context<DispatchError>| {
+                                   handler_log.borrow_mut().push("ping".to_string());
+                                   context
+                               }).unwrap();
+
+        dispatcher.dispatch(Ping).wait().unwrap();
+        assert_eq!(vec!["before outer", "enter", "before inner", "ping", "after inner",
+                        "leave after 3", "after outer"],
+                   *log.borrow());
+    }
+
+    #[test]
+    fn test_around_can_skip_rest_of_chain() {
+        let log: Log = Rc::new(RefCell::new(vec![]));
+        let dispatcher = EventDispatcher::new();
+        let skip = around(|context: Context<DispatchError>, _next: Next<DispatchError>| context);
+        dispatcher.register_fn(vec![record(&log, "outer"), Box::new(skip), record(&log, "inner")],
+                               |Ping, context: Context<DispatchError>| context).unwrap();
+
+        dispatcher.dispatch(Ping).wait().unwrap();
+        assert_eq!(vec!["before outer", "after outer"], *log.borrow());
+    }
+}
@@ -0,0 +1,228 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use futures::{stream,Future,Stream};
+use tokio_core::reactor::Handle;
+
+use super::{Context,Db,DispatchError,Effect,EventDispatcher,EventMeta,Lifecycle,Phase};
+
+type Dispatching<E> = Box<Future<Item = Context<E>, Error = E>>;
+
+type DispatchFn<E> = Box<FnOnce(&EventDispatcher<E>, Option<&EventMeta>) -> Dispatching<E>>;
+
+/// An event of any type, ready to be dispatched as part of a batch.
+pub struct AnyEvent<E>(DispatchFn<E>);
+
+impl<E> AnyEvent<E>
+where E: 'static + From<DispatchError>,
+{
+    pub fn new<Ev: 'static>(event: Ev) -> AnyEvent<E> {
+        AnyEvent(Box::new(move |dispatcher: &EventDispatcher<E>, parent: Option<&EventMeta>| {
+            match parent {
+                Some(parent) => Box::new(dispatcher.dispatch_caused_by(event, parent)) as Dispatching<E>,
+                None => Box::new(dispatcher.dispatch(event)),
+            }
+        }))
+    }
+
+    fn dispatch(self, dispatcher: &EventDispatcher<E>, parent: Option<&EventMeta>) -> Dispatching<E> {
+        (self.0)(dispatcher, parent)
+    }
+}
+
+/// A `Vec` of `AnyEvent`s, for `Dispatcher::dispatch_all`, made from
+/// events of any types:
+///
+/// ```ignore
+/// dispatcher.dispatch_all(events![Save, Notify("saved")])
+/// ```
+#[macro_export]
+macro_rules! events {
+    { $( $event:expr ),* $(,)? } => {
+        vec![ $( $crate::AnyEvent::new($event) ),* ]
+    }
+}
+
+/// Saves some state when a batch starts, returning how to put it back.
+type Snapshot = Box<FnOnce() -> Box<FnOnce()>>;
+
+/// An effect dispatching several events one after the other, each
+/// starting once the previous one has completed. Created by
+/// `Dispatcher::dispatch_all`.
+///
+/// The first dispatch that fails stops the batch. If the batch was
+/// made atomic against a `Db`, its state is then restored to what it
+/// was when the batch started. Other effects of the events that did
+/// complete are not undone.
+///
+/// The whole state is restored, so an atomic batch needs the `Db` to
+/// itself while it runs: changes made by other dispatches in the
+/// meantime are rolled back with it. Dispatches started by the
+/// batch's events are not part of the batch and are not waited for;
+/// whatever they change after a rollback is kept.
+pub struct DispatchAll<E> {
+    events: Vec<AnyEvent<E>>,
+    handle: Handle,
+    dispatcher: EventDispatcher<E>,
+    lifecycle: Option<Lifecycle>,
+    parent: Option<EventMeta>,
+    snapshot: Option<Snapshot>,
+}
+
+impl<E> DispatchAll<E>
+where E: 'static + From<DispatchError>,
+{
+    pub fn new<I>(events: I, handle: &Handle, dispatcher: &EventDispatcher<E>) -> DispatchAll<E>
+    where I: IntoIterator<Item = AnyEvent<E>>,
+    {
+        DispatchAll {
+            events: events.into_iter().collect(),
+            handle: handle.clone(),
+            dispatcher: dispatcher.clone(),
+            lifecycle: None,
+            parent: None,
+            snapshot: None,
+        }
+    }
+
+    pub fn tracked_by(mut self, lifecycle: &Lifecycle) -> DispatchAll<E> {
+        self.lifecycle = Some(lifecycle.clone());
+        self
+    }
+
+    pub fn caused_by(mut self, parent: &EventMeta) -> DispatchAll<E> {
+        self.parent = Some(parent.clone());
+        self
+    }
+
+    /// Restore `db` to its state at the start of the batch if any of
+    /// the events fails. No other dispatch should change `db` until the
+    /// batch completes.
+    pub fn atomic<S: 'static + Clone>(mut self, db: &Db<S>) -> DispatchAll<E> {
+        let db = db.clone();
+        self.snapshot = Some(Box::new(move || {
+            let state = db.update();
+            Box::new(move || db.restore(state)) as Box<FnOnce()>
+        }));
+        self
+    }
+}
+
+impl<E> Effect for DispatchAll<E>
+where E: 'static + From<DispatchError>,
+{
+    fn action(self: Box<Self>) {
+        let DispatchAll { events, handle, dispatcher, lifecycle, parent, snapshot } = *self;
+        let rollback = snapshot.map(|snapshot| snapshot());
+        let dispatched = stream::iter_ok::<_, E>(events)
+            .for_each(move |event| event.dispatch(&dispatcher, parent.as_ref()).map(|_| ()))
+            .map_err(move |_| {
+                if let Some(rollback) = rollback {
+                    rollback();
+                }
+            });
+        match lifecycle {
+            Some(lifecycle) => handle.spawn(lifecycle.track(dispatched)),
+            None => handle.spawn(dispatched),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("dispatch {} events in order", self.events.len())
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Dispatch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    use {App,Dispatcher};
+
+    #[derive(Clone,Debug,Default,PartialEq)]
+    struct Log(Vec<&'static str>);
+
+    struct Start(Vec<&'static str>, bool);
+    struct Append(&'static str);
+    struct Fail;
+
+    fn run(atomic: bool, events: Vec<&'static str>) -> Vec<&'static str> {
+        let mut core = Core::new().unwrap();
+        let app: App<Log> = App::new(core.handle());
+        let db = Rc::new(RefCell::new(None));
+
+        let start_db = Rc::clone(&db);
+        app.register_fn(move |Start(names, atomic), mut context: Context<()>| {
+            let log = context.coeffects.get::<Db<Log>>().unwrap().clone();
+            *start_db.borrow_mut() = Some(log.clone());
+            let batch = names.into_iter().map(|name| match name {
+                "fail" => AnyEvent::new(Fail),
+                name => AnyEvent::new(Append(name)),
+            });
+            let effect = {
+                let dispatcher = context.coeffects.get::<Dispatcher<()>>().unwrap();
+                if atomic { dispatcher.dispatch_all_atomic(batch, &log) } else { dispatcher.dispatch_all(batch) }
+            };
+            context.effects.push(effect);
+            context
+        }).unwrap();
+        app.register_fn(|Append(name), mut context: Context<()>| {
+            let db = context.coeffects.get::<Db<Log>>().unwrap().clone();
+            context.push_effect(db.mutate(move |log: &mut Log| log.0.push(name)));
+            context
+        }).unwrap();
+        app.register_fn(|Fail, _context: Context<()>| future::err::<Context<()>, ()>(())).unwrap();
+
+        core.run(app.dispatch(Start(events, atomic))).ok().unwrap();
+        for _ in 0..10 {
+            core.turn(Some(Duration::from_millis(1)));
+        }
+
+        let log = db.borrow().as_ref().unwrap().update();
+        log.0
+    }
+
+    #[test]
+    fn test_dispatch_all_in_order() {
+        assert_eq!(vec!["a", "b", "c"], run(false, vec!["a", "b", "c"]));
+    }
+
+    #[test]
+    fn test_failure_stops_batch() {
+        assert_eq!(vec!["a"], run(false, vec!["a", "fail", "b"]));
+    }
+
+    #[test]
+    fn test_atomic_batch_rolls_back() {
+        assert_eq!(Vec::<&str>::new(), run(true, vec!["a", "fail", "b"]));
+    }
+
+    #[test]
+    fn test_events_macro_mixes_types() {
+        let core = Core::new().unwrap();
+        let batch = DispatchAll::new(events![Append("a"), Fail,], &core.handle(), &EventDispatcher::<()>::new());
+        assert_eq!("dispatch 2 events in order", batch.describe());
+    }
+}
@@ -0,0 +1,113 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Handle returned alongside a cancellable dispatch. Cancelling stops
+/// the chain at the next interceptor boundary; the interceptor that
+/// is currently running is allowed to finish.
+#[derive(Clone,Default)]
+pub struct CancelHandle(Rc<Cell<bool>>);
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle(Rc::new(Cell::new(false)))
+    }
+
+    pub fn cancel(&self) {
+        self.0.set(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use futures::{future,Future};
+
+    use {Context,DispatchError,Event,EventDispatcher,Interceptor};
+
+    struct Ping;
+
+    impl Event<DispatchError> for Ping {
+        fn handle(self: Box<Self>, context: Context<DispatchError>) -> Box<Future<Item = Context<DispatchError>,
+                                                                                    Error = DispatchError>> {
+            Box::new(future::ok(context))
+        }
+    }
+
+    #[test]
+    fn test_cancelled_dispatch_stops_chain() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.register_event::<Ping>(vec![]).unwrap();
+
+        let (dispatched, cancel) = dispatcher.dispatch_cancellable(Ping);
+        cancel.cancel();
+
+        match dispatched.wait() {
+            Err(DispatchError::Cancelled { .. }) => {},
+            _ => panic!("expected the dispatch to be cancelled"),
+        }
+    }
+
+    #[test]
+    fn test_uncancelled_dispatch_completes() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.register_event::<Ping>(vec![]).unwrap();
+
+        let (dispatched, cancel) = dispatcher.dispatch_cancellable(Ping);
+
+        assert!(dispatched.wait().is_ok());
+        assert!(!cancel.is_cancelled());
+    }
+
+    /// Cancels the dispatch it is part of from its own `before`.
+    struct CancelOnEnter(Rc<Cell<bool>>);
+
+    impl Interceptor for CancelOnEnter {
+        type Error = DispatchError;
+
+        fn before(&self, context: Context<DispatchError>) -> Box<Future<Item = Context<DispatchError>,
+                                                                         Error = DispatchError>> {
+            context.cancel.as_ref().unwrap().cancel();
+            Box::new(future::ok(context))
+        }
+
+        fn error(&self, _error: &DispatchError) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn test_cancelling_from_before_reports_to_that_interceptor() {
+        let dispatcher = EventDispatcher::new();
+        let errored = Rc::new(Cell::new(false));
+        dispatcher.register_event::<Ping>(vec![Box::new(CancelOnEnter(Rc::clone(&errored)))]).unwrap();
+
+        let (dispatched, _cancel) = dispatcher.dispatch_cancellable(Ping);
+
+        match dispatched.wait() {
+            Err(DispatchError::Cancelled { .. }) => {},
+            _ => panic!("expected the dispatch to be cancelled"),
+        }
+        assert!(errored.get());
+    }
+}
@@ -0,0 +1,172 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::any::type_name;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use futures::{future,Future};
+
+use super::{Context,Interceptor};
+use around::{AroundInterceptor,Next,around};
+
+/// What a closure handler or interceptor may return: either the
+/// `Context` itself, for synchronous work, or a future resolving to it.
+pub trait IntoContextFuture<E> {
+    fn into_context_future(self) -> Box<Future<Item = Context<E>, Error = E>>;
+}
+
+impl<E: 'static> IntoContextFuture<E> for Context<E> {
+    fn into_context_future(self) -> Box<Future<Item = Context<E>, Error = E>> {
+        Box::new(future::ok(self))
+    }
+}
+
+impl<E, F> IntoContextFuture<E> for F
+where F: 'static + Future<Item = Context<E>, Error = E>,
+{
+    fn into_context_future(self) -> Box<Future<Item = Context<E>, Error = E>> {
+        Box::new(self)
+    }
+}
+
+/// An interceptor that runs `f` on the way into the chain.
+pub fn before_fn<F, R, E>(f: F) -> BeforeFn<F, E>
+where F: Fn(Context<E>) -> R,
+      R: IntoContextFuture<E>,
+{
+    BeforeFn { f, phantom: PhantomData }
+}
+
+/// An interceptor that runs `f` on the way back out of the chain.
+pub fn after_fn<F, R, E>(f: F) -> AfterFn<F, E>
+where F: Fn(Context<E>) -> R,
+      R: IntoContextFuture<E>,
+{
+    AfterFn { f, phantom: PhantomData }
+}
+
+/// An interceptor that runs `f` with the context and the `Next` that
+/// takes it through the rest of the chain; see `Around`.
+pub fn around_fn<F, R, E>(f: F) -> AroundInterceptor<F, E>
+where F: Fn(Context<E>, Next<E>) -> R,
+      R: IntoContextFuture<E>,
+{
+    around(f)
+}
+
+pub struct BeforeFn<F, E> {
+    f: F,
+    phantom: PhantomData<E>,
+}
+
+impl<F, R, E> Interceptor for BeforeFn<F, E>
+where F: 'static + Fn(Context<E>) -> R,
+      R: IntoContextFuture<E>,
+      E: 'static,
+{
+    type Error = E;
+
+    fn before(&self, context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        (self.f)(context).into_context_future()
+    }
+}
+
+pub struct AfterFn<F, E> {
+    f: F,
+    phantom: PhantomData<E>,
+}
+
+impl<F, R, E> Interceptor for AfterFn<F, E>
+where F: 'static + Fn(Context<E>) -> R,
+      R: IntoContextFuture<E>,
+      E: 'static,
+{
+    type Error = E;
+
+    fn after(&self, context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        (self.f)(context).into_context_future()
+    }
+}
+
+/// The last interceptor of a chain registered with
+/// `EventDispatcher::register_fn`: hands the event to the closure.
+pub struct FnHandler<Ev, F, E> {
+    event: RefCell<Option<Ev>>,
+    f: Rc<F>,
+    phantom: PhantomData<E>,
+}
+
+impl<Ev, F, E> FnHandler<Ev, F, E> {
+    pub fn new(event: Ev, f: Rc<F>) -> FnHandler<Ev, F, E> {
+        FnHandler { event: RefCell::new(Some(event)), f, phantom: PhantomData }
+    }
+}
+
+impl<Ev, F, R, E> Interceptor for FnHandler<Ev, F, E>
+where Ev: 'static,
+      F: 'static + Fn(Ev, Context<E>) -> R,
+      R: IntoContextFuture<E>,
+      E: 'static,
+{
+    type Error = E;
+
+    fn name(&self) -> &str {
+        type_name::<Ev>()
+    }
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        context.handled = true;
+        let event = self.event.borrow_mut().take();
+        (self.f)(event.unwrap(), context).into_context_future()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use EventDispatcher;
+    use tests::State;
+
+    struct Multiply(u8);
+
+    #[test]
+    fn test_sync_and_async_closures() {
+        let dispatcher: EventDispatcher<()> = EventDispatcher::new();
+        let set = before_fn(|mut context: Context<()>| { context.coeffects.insert(State(1)); context });
+        let scale = after_fn(|mut context: Context<()>| {
+            let n = context.coeffects.get::<State>().unwrap().0;
+            context.coeffects.insert(State(n * 10));
+            future::ok(context)
+        });
+        dispatcher.register_fn::<Multiply, _>(vec![Box::new(set), Box::new(scale)], |event, mut context| {
+            let n = context.coeffects.get::<State>().unwrap().0;
+            context.coeffects.insert(State(n * event.0));
+            context
+        }).unwrap();
+
+        let context = dispatcher.dispatch(Multiply(3)).wait().ok().unwrap();
+        assert_eq!(Some(&State(30)), context.coeffects.get::<State>());
+    }
+
+    #[test]
+    fn test_unregistered_dispatch_fails() {
+        let dispatcher: EventDispatcher<::DispatchError> = EventDispatcher::new();
+        assert_eq!(Err(::DispatchError::Unregistered { event: type_name::<Multiply>() }),
+                   dispatcher.dispatch(Multiply(3)).wait().map(|_| ()));
+    }
+}
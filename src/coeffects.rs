@@ -45,7 +45,7 @@ impl<C, E> InjectCoeffect<C, E>
 }
 
 impl<C, E> Interceptor for InjectCoeffect<C, E>
-where C: NewCoeffect,
+where C: 'static + NewCoeffect,
       E: 'static,
 {
     type Error = E;
@@ -0,0 +1,192 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Interceptors that decide what runs next.
+//!
+//! Each of these does its work in `before` by putting other
+//! interceptors at the front of the queue, where they then run as
+//! ordinary members of the chain, `after` included. They keep no
+//! per-dispatch state, so they can be shared between events.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use futures::{future,Future};
+
+use super::{Context,Interceptor};
+
+type Interceptors<E> = Vec<Rc<Box<Interceptor<Error = E>>>>;
+
+fn shared<E>(interceptors: Vec<Box<Interceptor<Error = E>>>) -> Interceptors<E> {
+    interceptors.into_iter().map(Rc::new).collect()
+}
+
+/// Put `interceptors` at the front of the queue, keeping their order.
+fn enqueue<E>(context: &mut Context<E>, interceptors: &[Rc<Box<Interceptor<Error = E>>>]) {
+    for interceptor in interceptors.iter().rev() {
+        context.queue.push_front(Rc::clone(interceptor));
+    }
+}
+
+/// Runs `interceptor` next only if `predicate` holds for the context.
+pub fn when<P, E>(predicate: P, interceptor: Box<Interceptor<Error = E>>) -> When<P, E>
+where P: Fn(&Context<E>) -> bool,
+{
+    When { predicate, interceptor: vec![Rc::new(interceptor)] }
+}
+
+/// A single interceptor that runs `interceptors` in order.
+pub fn chain<E>(interceptors: Vec<Box<Interceptor<Error = E>>>) -> Chain<E> {
+    Chain(shared(interceptors))
+}
+
+/// Runs the chain that `key` selects for the context. If no chain
+/// matches, nothing is run unless a fallback is set with `otherwise`.
+pub fn route<F, K, E>(key: F, routes: HashMap<K, Vec<Box<Interceptor<Error = E>>>>) -> Route<F, K, E>
+where F: Fn(&Context<E>) -> K,
+      K: Eq + Hash,
+{
+    Route {
+        key,
+        routes: routes.into_iter().map(|(k, chain)| (k, shared(chain))).collect(),
+        otherwise: vec![],
+        phantom: PhantomData,
+    }
+}
+
+pub struct When<P, E> {
+    predicate: P,
+    interceptor: Interceptors<E>,
+}
+
+impl<P, E> Interceptor for When<P, E>
+where P: 'static + Fn(&Context<E>) -> bool,
+      E: 'static,
+{
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        if (self.predicate)(&context) {
+            enqueue(&mut context, &self.interceptor);
+        }
+        Box::new(future::ok(context))
+    }
+}
+
+pub struct Chain<E>(Interceptors<E>);
+
+impl<E: 'static> Interceptor for Chain<E> {
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        enqueue(&mut context, &self.0);
+        Box::new(future::ok(context))
+    }
+}
+
+pub struct Route<F, K, E> {
+    key: F,
+    routes: HashMap<K, Interceptors<E>>,
+    otherwise: Interceptors<E>,
+    phantom: PhantomData<E>,
+}
+
+impl<F, K, E> Route<F, K, E> {
+    /// Run `interceptors` when no route matches.
+    pub fn otherwise(mut self, interceptors: Vec<Box<Interceptor<Error = E>>>) -> Route<F, K, E> {
+        self.otherwise = shared(interceptors);
+        self
+    }
+}
+
+impl<F, K, E> Interceptor for Route<F, K, E>
+where F: 'static + Fn(&Context<E>) -> K,
+      K: 'static + Eq + Hash,
+      E: 'static,
+{
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        let key = (self.key)(&context);
+        let chain = self.routes.get(&key).unwrap_or(&self.otherwise);
+        enqueue(&mut context, chain);
+        Box::new(future::ok(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    use {EventDispatcher,before_fn};
+
+    #[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+    enum Mode { Adding, Removing, Quitting }
+
+    struct Input;
+
+    struct Record(&'static str, Rc<RefCell<Vec<&'static str>>>);
+
+    impl Interceptor for Record {
+        type Error = ();
+
+        fn before(&self, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            self.1.borrow_mut().push(self.0);
+            Box::new(future::ok(context))
+        }
+    }
+
+    fn run(mode: Mode, verbose: bool) -> Vec<&'static str> {
+        let log = Rc::new(RefCell::new(vec![]));
+        let record = |name| Box::new(Record(name, Rc::clone(&log))) as Box<Interceptor<Error = ()>>;
+
+        let mut routes = HashMap::new();
+        routes.insert(Mode::Adding, vec![record("check"), record("add")]);
+        routes.insert(Mode::Removing, vec![Box::new(chain(vec![record("check"), record("parse")])),
+                                           record("remove")]);
+        let router = route(|context: &Context<()>| *context.coeffects.get::<Mode>().unwrap(), routes)
+            .otherwise(vec![record("quit")]);
+        let verbose_only = when(|context: &Context<()>| *context.coeffects.get::<bool>().unwrap(),
+                                record("log"));
+        let inject = before_fn(move |mut context: Context<()>| {
+            context.coeffects.insert(mode);
+            context.coeffects.insert(verbose);
+            context
+        });
+
+        let dispatcher = EventDispatcher::new();
+        dispatcher.register_fn(vec![Box::new(inject), Box::new(verbose_only), Box::new(router), record("prompt")],
+                               |Input, context: Context<()>| context).unwrap();
+        dispatcher.dispatch(Input).wait().ok().unwrap();
+        let log = log.borrow().clone();
+        log
+    }
+
+    #[test]
+    fn test_route_enqueues_matching_chain() {
+        assert_eq!(vec!["check", "add", "prompt"], run(Mode::Adding, false));
+        assert_eq!(vec!["check", "parse", "remove", "prompt"], run(Mode::Removing, false));
+        assert_eq!(vec!["quit", "prompt"], run(Mode::Quitting, false));
+    }
+
+    #[test]
+    fn test_when_runs_only_if_predicate_holds() {
+        assert_eq!(vec!["log", "quit", "prompt"], run(Mode::Quitting, true));
+    }
+}
@@ -39,6 +39,11 @@ where State: Clone,
     pub fn update(&self) -> State {
         self.0.borrow().clone()
     }
+
+    /// Replace the state wholesale, e.g. with one saved by `update`.
+    pub fn restore(&self, state: State) {
+        *self.0.borrow_mut() = state;
+    }
 }
 
 impl<S> Clone for Db<S> {
@@ -86,9 +91,7 @@ mod tests {
                 let db = context.coeffects.get::<Db<State>>().unwrap();
                 assert_eq!(self.initial, db.borrow().0);
                 let inc = self.inc;
-                let new_state = db.update();
-                new_state.0 += inc;
-                context.effects.push(Box::new(new_state));
+                context.effects.push(Box::new(db.mutate(move |state: &mut State| state.0 += inc)));
             }
             Box::new(future::ok(context))
         }
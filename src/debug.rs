@@ -0,0 +1,499 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A time-travel debugger, served over a Unix domain socket.
+
+use std::cell::RefCell;
+use std::collections::{HashMap,VecDeque};
+use std::io::{self,BufReader};
+use std::path::Path;
+use std::rc::Rc;
+
+use futures::{future,stream,Future,Stream};
+use futures::sync::mpsc::{self,UnboundedSender};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self,Value};
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncRead;
+use tokio_uds::{UnixListener,UnixStream};
+
+use super::{Change,Context,Db,EventDispatcher,EventId,EventMeta,Interceptor};
+use diff::serialized;
+
+const DEFAULT_CAPACITY: usize = 100;
+
+type Dispatching = Box<Future<Item = (), Error = ()>>;
+
+/// Dispatches an event deserialized from JSON.
+type Replay = Rc<Fn(Value, &EventDispatcher<()>) -> Result<Dispatching, serde_json::Error>>;
+
+/// Starts serializing an event type on the dispatcher it is given.
+type Attach = Rc<Fn(&EventDispatcher<()>)>;
+
+/// The name an event was registered under and its serialized form,
+/// left in the context of its dispatch.
+struct Payload(String, Value);
+
+/// A dispatch that has started and not yet finished: the state of
+/// the `Db` when it started, and its event if serialized.
+struct Pending<S> {
+    before: S,
+    payload: Option<Payload>,
+}
+
+struct Frame<S> {
+    meta: EventMeta,
+    failed: bool,
+    payload: Option<Payload>,
+    trace: Vec<String>,
+    changes: Vec<Change>,
+    state: S,
+}
+
+impl<S> Frame<S> {
+    fn to_line(&self) -> String {
+        let (name, payload) = match self.payload {
+            Some(Payload(ref name, ref payload)) => (Some(name.as_str()), payload.clone()),
+            None => (None, Value::Null),
+        };
+        let changes: Vec<Value> = self.changes.iter().map(|change| json!({
+            "path": change.path,
+            "before": change.before,
+            "after": change.after,
+        })).collect();
+        line(json!({
+            "type": "event",
+            "outcome": if self.failed { "error" } else { "ok" },
+            "id": self.meta.id.as_u64(),
+            "parent": self.meta.parent.map(|parent| parent.as_u64()),
+            "root": self.meta.root.as_u64(),
+            "event": self.meta.event,
+            "name": name,
+            "payload": payload,
+            "trace": self.trace,
+            "changes": changes,
+        }))
+    }
+}
+
+fn line(value: Value) -> String {
+    format!("{}\n", value)
+}
+
+struct Inner<S> {
+    capacity: usize,
+    diff: fn(&S, &S) -> Vec<Change>,
+    frames: VecDeque<Frame<S>>,
+    pending: HashMap<EventId, Pending<S>>,
+    events: HashMap<String, Replay>,
+    attach: Vec<Attach>,
+    dispatcher: Option<EventDispatcher<()>>,
+    clients: Vec<UnboundedSender<String>>,
+}
+
+/// A `Debugger` records each finished dispatch of an app as a frame:
+/// its metadata, whether it failed, the interceptors it ran (unknown
+/// for a failed one), what it changed in the `Db`,
+/// the state it left behind and, for events registered with
+/// `Debugger::event`, the event itself serialized as JSON.
+///
+/// Clients talk to it in lines of JSON. On connecting they are sent
+/// every recorded frame, then each new one as it is recorded:
+///
+/// ```text
+/// {"type":"event","outcome":"ok","id":4,"parent":null,"root":4,"event":"todo::Add","name":"add",
+///  "payload":"milk","trace":["..."],"changes":[{"path":"[0]","before":null,"after":"\"milk\""}]}
+/// ```
+///
+/// and may send commands, each answered with `{"type":"ok",...}` or
+/// `{"type":"error","message":...}`:
+///
+/// - `{"cmd":"jump","id":4}` puts the `Db` back to the state frame 4
+///   left it in.
+/// - `{"cmd":"replay","from":4}` jumps to frame 4, forgets the frames
+///   after it and dispatches their events again, in order. Only the
+///   events that were dispatched from outside a handler, and that were
+///   registered with `Debugger::event`, are replayed; the events they
+///   caused are dispatched again by their handlers.
+/// - `{"cmd":"dispatch","event":"add","payload":"eggs"}` dispatches the
+///   event registered as `add`, deserialized from `payload`.
+///
+/// Install it with `AppBuilder::debugger` and start serving with
+/// `App::serve_debugger`. A `Debugger` is a cheap handle; clones share
+/// the same recording.
+pub struct Debugger<S>(Rc<RefCell<Inner<S>>>);
+
+impl<S> Clone for Debugger<S> {
+    fn clone(&self) -> Debugger<S> {
+        Debugger(Rc::clone(&self.0))
+    }
+}
+
+impl<S> Default for Debugger<S>
+where S: 'static + Clone + Serialize,
+{
+    fn default() -> Debugger<S> {
+        Debugger::new()
+    }
+}
+
+impl<S> Debugger<S>
+where S: 'static + Clone,
+{
+    /// A debugger keeping the last 100 frames.
+    pub fn new() -> Debugger<S>
+    where S: Serialize,
+    {
+        Debugger::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// A debugger keeping the last `capacity` frames.
+    pub fn with_capacity(capacity: usize) -> Debugger<S>
+    where S: Serialize,
+    {
+        Debugger(Rc::new(RefCell::new(Inner {
+            capacity: capacity.max(1),
+            diff: serialized::diff::<S>,
+            frames: VecDeque::new(),
+            pending: HashMap::new(),
+            events: HashMap::new(),
+            attach: vec![],
+            dispatcher: None,
+            clients: vec![],
+        })))
+    }
+
+    /// Record `Ev` events serialized, and let clients dispatch and
+    /// replay them under `name`.
+    pub fn event<Ev>(&self, name: &str)
+    where Ev: 'static + Serialize + DeserializeOwned,
+    {
+        let replay: Replay = Rc::new(|payload, dispatcher| {
+            let event: Ev = serde_json::from_value(payload)?;
+            Ok(Box::new(dispatcher.dispatch(event).map(|_| ())) as Dispatching)
+        });
+        let tag = name.to_string();
+        let attach: Attach = Rc::new(move |dispatcher| {
+            let tag = tag.clone();
+            dispatcher.inspect(move |event: &Ev, context: &mut Context<()>| {
+                match serde_json::to_value(event) {
+                    Ok(payload) => {
+                        context.coeffects.insert(Payload(tag.clone(), payload));
+                    },
+                    Err(e) => error!("failed to serialize {} for the debugger: {}", tag, e),
+                }
+            });
+        });
+        let dispatcher = self.0.borrow().dispatcher.clone();
+        if let Some(dispatcher) = dispatcher {
+            attach(&dispatcher);
+        }
+        let mut inner = self.0.borrow_mut();
+        inner.events.insert(name.to_string(), replay);
+        inner.attach.push(attach);
+    }
+
+    /// Serialize the events registered with `event` as `dispatcher`
+    /// dispatches them.
+    pub fn attach(&self, dispatcher: &EventDispatcher<()>) {
+        let attach = {
+            let mut inner = self.0.borrow_mut();
+            inner.dispatcher = Some(dispatcher.clone());
+            inner.attach.clone()
+        };
+        for attach in attach {
+            attach(dispatcher);
+        }
+    }
+
+    /// The interceptor recording dispatches against `db`. It has to
+    /// come first in the chain to see all of it.
+    pub fn interceptor(&self, db: &Db<S>) -> DebugInterceptor<S> {
+        DebugInterceptor { debugger: self.clone(), db: db.clone() }
+    }
+
+    /// Record the end of the dispatch described by `meta`, if it was
+    /// seen starting.
+    fn finish(&self, meta: EventMeta, failed: bool, trace: Vec<String>, state: S) {
+        let (pending, diff) = {
+            let mut inner = self.0.borrow_mut();
+            (inner.pending.remove(&meta.id), inner.diff)
+        };
+        if let Some(Pending { before, payload }) = pending {
+            self.record(Frame { meta, failed, payload, trace, changes: diff(&before, &state), state });
+        }
+    }
+
+    fn record(&self, frame: Frame<S>) {
+        let line = frame.to_line();
+        let mut inner = self.0.borrow_mut();
+        inner.frames.push_back(frame);
+        while inner.frames.len() > inner.capacity {
+            inner.frames.pop_front();
+        }
+        inner.clients.retain(|client| client.unbounded_send(line.clone()).is_ok());
+    }
+
+    /// Listen for clients on the Unix domain socket at `path`. They
+    /// can move `db` through its recorded states.
+    pub fn serve<P: AsRef<Path>>(&self, path: P, handle: &Handle, db: &Db<S>) -> io::Result<()> {
+        let listener = UnixListener::bind(path, handle)?;
+        let debugger = self.clone();
+        let spawner = handle.clone();
+        let db = db.clone();
+        handle.spawn(listener.incoming()
+                     .for_each(move |(stream, _)| {
+                         debugger.connect(stream, &spawner, &db);
+                         Ok(())
+                     })
+                     .map_err(|e| error!("debug server stopped: {}", e)));
+        Ok(())
+    }
+
+    fn connect(&self, stream: UnixStream, handle: &Handle, db: &Db<S>) {
+        let (reader, writer) = stream.split();
+        let (tx, rx) = mpsc::unbounded::<String>();
+        {
+            let mut inner = self.0.borrow_mut();
+            for frame in inner.frames.iter() {
+                let _ = tx.unbounded_send(frame.to_line());
+            }
+            inner.clients.push(tx.clone());
+        }
+        handle.spawn(rx.fold(writer, |writer, line| {
+            ::tokio_io::io::write_all(writer, line).map(|(writer, _)| writer).map_err(|_| ())
+        }).map(|_| ()));
+
+        let debugger = self.clone();
+        let spawner = handle.clone();
+        let db = db.clone();
+        handle.spawn(::tokio_io::io::lines(BufReader::new(reader))
+                     .for_each(move |command| {
+                         let reply = match debugger.command(&command, &spawner, &db) {
+                             Ok(reply) => reply,
+                             Err(message) => json!({ "type": "error", "message": message }),
+                         };
+                         let _ = tx.unbounded_send(line(reply));
+                         Ok(())
+                     })
+                     .map_err(|e| debug!("debug client disconnected: {}", e)));
+    }
+
+    fn command(&self, command: &str, handle: &Handle, db: &Db<S>) -> Result<Value, String> {
+        let command: Value = serde_json::from_str(command).map_err(|e| e.to_string())?;
+        match command["cmd"].as_str() {
+            Some("jump") => {
+                let id = command["id"].as_u64().ok_or("jump needs an id")?;
+                self.jump(id, db)?;
+                Ok(json!({ "type": "ok", "cmd": "jump", "id": id }))
+            },
+            Some("replay") => {
+                let from = command["from"].as_u64().ok_or("replay needs a frame to start from")?;
+                let (replayed, skipped) = self.replay(from, handle, db)?;
+                Ok(json!({ "type": "ok", "cmd": "replay", "replayed": replayed, "skipped": skipped }))
+            },
+            Some("dispatch") => {
+                let name = command["event"].as_str().ok_or("dispatch needs an event")?;
+                let (replay, dispatcher) = self.replayable(name)?;
+                let dispatching = replay(command["payload"].clone(), &dispatcher).map_err(|e| e.to_string())?;
+                handle.spawn(dispatching);
+                Ok(json!({ "type": "ok", "cmd": "dispatch", "event": name }))
+            },
+            _ => Err(format!("unknown command: {}", command)),
+        }
+    }
+
+    fn replayable(&self, name: &str) -> Result<(Replay, EventDispatcher<()>), String> {
+        let inner = self.0.borrow();
+        let replay = inner.events.get(name).cloned().ok_or_else(|| format!("unknown event: {}", name))?;
+        let dispatcher = inner.dispatcher.clone().ok_or("the debugger is not attached to an app")?;
+        Ok((replay, dispatcher))
+    }
+
+    fn position(&self, id: u64) -> Result<usize, String> {
+        self.0.borrow().frames.iter()
+            .position(|frame| frame.meta.id.as_u64() == id)
+            .ok_or_else(|| format!("no frame #{}", id))
+    }
+
+    fn jump(&self, id: u64, db: &Db<S>) -> Result<(), String> {
+        let position = self.position(id)?;
+        let state = self.0.borrow().frames[position].state.clone();
+        db.restore(state);
+        Ok(())
+    }
+
+    fn replay(&self, from: u64, handle: &Handle, db: &Db<S>) -> Result<(usize, usize), String> {
+        // Every frame is checked before the Db or the recording is
+        // touched, so that a replay that fails changes nothing.
+        let position = self.position(from)?;
+        let dispatcher = self.0.borrow().dispatcher.clone().ok_or("the debugger is not attached to an app")?;
+        let mut events = vec![];
+        let mut skipped = 0;
+        for frame in self.0.borrow().frames.iter().skip(position + 1).filter(|frame| frame.meta.is_root()) {
+            match frame.payload {
+                Some(Payload(ref name, ref payload)) => events.push((self.replayable(name)?.0, payload.clone())),
+                None => skipped += 1,
+            }
+        }
+        let replayed = events.len();
+        self.jump(from, db)?;
+        self.0.borrow_mut().frames.truncate(position + 1);
+        handle.spawn(stream::iter_ok::<_, ()>(events).for_each(move |(replay, payload)| {
+            let dispatching = replay(payload, &dispatcher).unwrap_or_else(|e| {
+                error!("failed to replay an event: {}", e);
+                Box::new(future::ok(()))
+            });
+            dispatching.then(|_| Ok(()))
+        }));
+        Ok((replayed, skipped))
+    }
+}
+
+/// Records each dispatch it is part of; see `Debugger::interceptor`.
+pub struct DebugInterceptor<S> {
+    debugger: Debugger<S>,
+    db: Db<S>,
+}
+
+impl<S> Interceptor for DebugInterceptor<S>
+where S: 'static + Clone,
+{
+    type Error = ();
+
+    fn before(&self, mut context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+        if let Some(id) = context.meta().map(|meta| meta.id) {
+            let pending = Pending { before: self.db.update(), payload: context.coeffects.remove::<Payload>() };
+            self.debugger.0.borrow_mut().pending.insert(id, pending);
+        }
+        Box::new(future::ok(context))
+    }
+
+    fn after(&self, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+        if let Some(meta) = context.meta().cloned() {
+            let trace = context.executed().iter().map(|name| name.to_string()).collect();
+            self.debugger.finish(meta, false, trace, self.db.update());
+        }
+        Box::new(future::ok(context))
+    }
+
+    fn error(&self, _error: &()) {
+        if let Some(meta) = EventMeta::current() {
+            self.debugger.finish(meta, true, vec![], self.db.update());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    use tokio_core::reactor::Core;
+
+    use App;
+
+    #[derive(Serialize,Deserialize)]
+    struct Add(String);
+
+    #[derive(Serialize,Deserialize)]
+    struct Fail;
+
+    struct Client(Option<BufReader<UnixStream>>);
+
+    impl Client {
+        fn send(&mut self, command: &str) {
+            writeln!(self.0.as_mut().unwrap().get_mut(), "{}", command).unwrap();
+        }
+
+        /// Run `core` until a line arrives, and parse it.
+        fn receive(&mut self, core: &mut Core) -> Value {
+            let reader = self.0.take().unwrap();
+            let (reader, line) = core.run(::tokio_io::io::read_until(reader, b'\n', vec![])).unwrap();
+            self.0 = Some(reader);
+            serde_json::from_slice(&line).unwrap()
+        }
+    }
+
+    /// Removes a file when dropped, whether or not the test passed.
+    struct TempFile<'a>(&'a Path);
+
+    impl<'a> Drop for TempFile<'a> {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(self.0);
+        }
+    }
+
+    #[test]
+    fn test_time_travel() {
+        let mut core = Core::new().unwrap();
+        let debugger: Debugger<Vec<String>> = Debugger::new();
+        debugger.event::<Add>("add");
+        debugger.event::<Fail>("fail");
+        let app: App<Vec<String>> = App::builder(core.handle()).debugger(&debugger).build();
+        let seen_db = Rc::new(RefCell::new(None));
+        let handler_db = Rc::clone(&seen_db);
+        app.register_fn(move |Add(todo), mut context: Context<()>| {
+            let db = context.coeffects.remove::<Db<Vec<String>>>().unwrap();
+            *handler_db.borrow_mut() = Some(db.clone());
+            context.push_effect(db.mutate(move |todos: &mut Vec<String>| todos.push(todo)));
+            context
+        }).unwrap();
+        app.register_fn(|Fail, _context: Context<()>| future::err::<Context<()>, ()>(())).unwrap();
+        let todos = || seen_db.borrow().as_ref().unwrap().update();
+
+        let path = env::temp_dir().join(format!("tokio-interceptor-debug-{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _cleanup = TempFile(&path);
+        app.serve_debugger(&path).unwrap();
+        core.run(app.dispatch(Add("milk".to_string()))).ok().unwrap();
+
+        let stream = UnixStream::connect(&path, &core.handle()).unwrap();
+        let mut client = Client(Some(BufReader::new(stream)));
+        let milk = client.receive(&mut core);
+        assert_eq!("ok", milk["outcome"]);
+        assert_eq!("add", milk["name"]);
+        assert_eq!("milk", milk["payload"]);
+        assert_eq!("\"milk\"", milk["changes"][0]["after"]);
+
+        client.send(r#"{"cmd":"dispatch","event":"add","payload":"eggs"}"#);
+        assert_eq!("ok", client.receive(&mut core)["type"]);
+        let eggs = client.receive(&mut core);
+        assert_eq!("eggs", eggs["payload"]);
+        assert_eq!(vec!["milk", "eggs"], todos());
+
+        client.send(&format!(r#"{{"cmd":"jump","id":{}}}"#, milk["id"]));
+        assert_eq!("ok", client.receive(&mut core)["type"]);
+        assert_eq!(vec!["milk"], todos());
+
+        client.send(&format!(r#"{{"cmd":"replay","from":{}}}"#, milk["id"]));
+        assert_eq!(1, client.receive(&mut core)["replayed"]);
+        assert_eq!("eggs", client.receive(&mut core)["payload"]);
+        assert_eq!(vec!["milk", "eggs"], todos());
+
+        client.send(r#"{"cmd":"dispatch","event":"remove"}"#);
+        assert_eq!("error", client.receive(&mut core)["type"]);
+
+        assert!(core.run(app.dispatch(Fail)).is_err());
+        let failed = client.receive(&mut core);
+        assert_eq!("error", failed["outcome"]);
+        assert_eq!("fail", failed["name"]);
+        assert_eq!(vec!["milk", "eggs"], todos());
+    }
+}
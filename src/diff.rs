@@ -0,0 +1,306 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64,Ordering};
+
+use futures::{future,Future};
+use futures::sync::mpsc::UnboundedSender;
+
+use super::{Context,Db,EventMeta,Interceptor};
+
+/// One difference between two values of a state, at `path` within
+/// it. `before` is `None` for something added, `after` for something
+/// removed.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Change {
+    pub path: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.before.as_ref(), self.after.as_ref()) {
+            (Some(before), Some(after)) => write!(f, "{}: {} -> {}", self.path, before, after),
+            (None, Some(after)) => write!(f, "+{}: {}", self.path, after),
+            (Some(before), None) => write!(f, "-{}: {}", self.path, before),
+            (None, None) => write!(f, "{}", self.path),
+        }
+    }
+}
+
+/// A state that can list what changed between two of its values.
+pub trait Diff {
+    fn diff(&self, after: &Self) -> Vec<Change>;
+}
+
+/// What a dispatch changed in the state.
+#[derive(Clone,Debug)]
+pub struct StateDiff {
+    pub event: &'static str,
+    pub meta: Option<EventMeta>,
+    pub changes: Vec<Change>,
+}
+
+type DiffFn<S> = Rc<Fn(&S, &S) -> Vec<Change>>;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The values of the `Db`s when the `DiffState`s of a dispatch saw it
+/// start, by the `DiffState` that took them.
+struct Before<S>(HashMap<u64, S>);
+
+/// Reports what each dispatch changed in a `Db`, comparing its value
+/// from before the rest of the chain with the one after it, once the
+/// effects of the dispatch have been performed. For that it has to
+/// come before `HandleEffects` in the chain.
+///
+/// Dispatches that changed something are logged at debug level, and
+/// sent to the `report_to` stream if there is one.
+pub struct DiffState<S, E> {
+    id: u64,
+    db: Db<S>,
+    diff: DiffFn<S>,
+    reports: Option<UnboundedSender<StateDiff>>,
+    phantom: PhantomData<E>,
+}
+
+impl<S, E> DiffState<S, E>
+where S: 'static + Clone,
+{
+    pub fn new(db: &Db<S>) -> DiffState<S, E>
+    where S: Diff,
+    {
+        DiffState::with(db, |before: &S, after: &S| before.diff(after))
+    }
+
+    /// Diff the values of `db` with `diff`.
+    pub fn with<F>(db: &Db<S>, diff: F) -> DiffState<S, E>
+    where F: 'static + Fn(&S, &S) -> Vec<Change>,
+    {
+        DiffState {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            db: db.clone(),
+            diff: Rc::new(diff),
+            reports: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Diff the values of `db` by their serialized form: maps and
+    /// structs by key, sequences by index, with paths like
+    /// `todos[2].done` and the values shown as JSON.
+    #[cfg(feature = "serde")]
+    pub fn serialized(db: &Db<S>) -> DiffState<S, E>
+    where S: ::serde::Serialize,
+    {
+        DiffState::with(db, serialized::diff)
+    }
+
+    /// Also send each report to `reports`.
+    pub fn report_to(mut self, reports: UnboundedSender<StateDiff>) -> DiffState<S, E> {
+        self.reports = Some(reports);
+        self
+    }
+}
+
+impl<S, E> Interceptor for DiffState<S, E>
+where S: 'static + Clone,
+      E: 'static,
+{
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        let state = self.db.update();
+        context.coeffects.entry::<Before<S>>().or_insert_with(|| Before(HashMap::new())).0.insert(self.id, state);
+        Box::new(future::ok(context))
+    }
+
+    fn after(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        let before = context.coeffects.get_mut::<Before<S>>().and_then(|before| before.0.remove(&self.id));
+        if let Some(before) = before {
+            let changes = (self.diff)(&before, &self.db.borrow());
+            if !changes.is_empty() {
+                let event = context.event_name().unwrap_or("unknown");
+                for change in &changes {
+                    debug!("{} changed {}", event, change);
+                }
+                if let Some(ref reports) = self.reports {
+                    let _ = reports.unbounded_send(StateDiff { event, meta: context.meta.clone(), changes });
+                }
+            }
+        }
+        Box::new(future::ok(context))
+    }
+}
+
+#[cfg(feature = "serde")]
+pub mod serialized {
+    use serde::Serialize;
+    use serde_json::{self,Value};
+
+    use super::Change;
+
+    pub fn diff<S: Serialize>(before: &S, after: &S) -> Vec<Change> {
+        let mut changes = vec![];
+        match (serde_json::to_value(before), serde_json::to_value(after)) {
+            (Ok(before), Ok(after)) => walk("", &before, &after, &mut changes),
+            (Err(e), _) | (_, Err(e)) => error!("failed to serialize state for diffing: {}", e),
+        }
+        changes
+    }
+
+    fn walk(path: &str, before: &Value, after: &Value, changes: &mut Vec<Change>) {
+        match (before, after) {
+            (Value::Object(before), Value::Object(after)) => {
+                for (key, b) in before {
+                    let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                    match after.get(key) {
+                        Some(a) => walk(&path, b, a, changes),
+                        None => changes.push(Change { path, before: Some(b.to_string()), after: None }),
+                    }
+                }
+                for (key, a) in after.iter().filter(|&(key, _)| !before.contains_key(key)) {
+                    let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                    changes.push(Change { path, before: None, after: Some(a.to_string()) });
+                }
+            },
+            (Value::Array(before), Value::Array(after)) => {
+                for i in 0..before.len().max(after.len()) {
+                    let path = format!("{}[{}]", path, i);
+                    match (before.get(i), after.get(i)) {
+                        (Some(b), Some(a)) => walk(&path, b, a, changes),
+                        (b, a) => changes.push(Change {
+                            path,
+                            before: b.map(Value::to_string),
+                            after: a.map(Value::to_string),
+                        }),
+                    }
+                }
+            },
+            (before, after) if before != after => changes.push(Change {
+                path: path.to_string(),
+                before: Some(before.to_string()),
+                after: Some(after.to_string()),
+            }),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Stream;
+    use futures::sync::mpsc;
+
+    use {EventDispatcher,HandleEffects};
+
+    #[derive(Clone,Debug,Default,PartialEq)]
+    struct Counter {
+        count: u32,
+        label: String,
+    }
+
+    impl Diff for Counter {
+        fn diff(&self, after: &Counter) -> Vec<Change> {
+            let mut changes = vec![];
+            if self.count != after.count {
+                changes.push(Change { path: "count".to_string(),
+                                      before: Some(self.count.to_string()),
+                                      after: Some(after.count.to_string()) });
+            }
+            if self.label != after.label {
+                changes.push(Change { path: "label".to_string(),
+                                      before: Some(format!("{:?}", self.label)),
+                                      after: Some(format!("{:?}", after.label)) });
+            }
+            changes
+        }
+    }
+
+    struct Increment;
+    struct Look;
+
+    #[test]
+    fn test_reports_changes_after_effects() {
+        let db = Db::new(Counter::default());
+        let (tx, rx) = mpsc::unbounded();
+        let dispatcher = EventDispatcher::<()>::new();
+        let chain = || -> Vec<Box<Interceptor<Error = ()>>> {
+            vec![Box::new(DiffState::new(&db).report_to(tx.clone())), Box::new(HandleEffects::new())]
+        };
+        let counter = db.clone();
+        dispatcher.register_fn(chain(), move |Increment, mut context: Context<()>| {
+            context.push_effect(counter.mutate(|c: &mut Counter| c.count += 1));
+            context
+        }).unwrap();
+        dispatcher.register_fn(chain(), |Look, context: Context<()>| context).unwrap();
+
+        dispatcher.dispatch(Increment).wait().unwrap();
+        dispatcher.dispatch(Look).wait().unwrap();
+        drop(tx);
+        drop(dispatcher);
+
+        let reports: Vec<StateDiff> = rx.collect().wait().unwrap();
+        assert_eq!(1, reports.len());
+        assert!(reports[0].event.ends_with("Increment"));
+        assert_eq!("count: 0 -> 1", reports[0].changes[0].to_string());
+    }
+
+    #[test]
+    fn test_diff_states_on_one_db_keep_their_own_snapshots() {
+        let db = Db::new(Counter::default());
+        let (tx, rx) = mpsc::unbounded();
+        let dispatcher = EventDispatcher::<()>::new();
+        let counter = db.clone();
+        dispatcher.register_fn(vec![Box::new(DiffState::new(&db).report_to(tx.clone())),
+                                    Box::new(DiffState::new(&db).report_to(tx.clone())),
+                                    Box::new(HandleEffects::new())],
+                               move |Increment, mut context: Context<()>| {
+                                   context.push_effect(counter.mutate(|c: &mut Counter| c.count += 1));
+                                   context
+                               }).unwrap();
+
+        dispatcher.dispatch(Increment).wait().unwrap();
+        drop(tx);
+        drop(dispatcher);
+
+        let reports: Vec<StateDiff> = rx.collect().wait().unwrap();
+        assert_eq!(2, reports.len());
+        assert_eq!(reports[0].changes, reports[1].changes);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialized_diff() {
+        use std::collections::BTreeMap;
+
+        let mut before = BTreeMap::new();
+        before.insert("todos", vec!["milk", "eggs"]);
+        before.insert("done", vec![]);
+        let mut after = before.clone();
+        after.insert("todos", vec!["milk"]);
+        after.insert("done", vec!["eggs"]);
+
+        let changes: Vec<String> = serialized::diff(&before, &after).iter().map(|c| c.to_string()).collect();
+        assert_eq!(vec!["+done[0]: \"eggs\"", "-todos[1]: \"eggs\""], changes);
+    }
+}
@@ -13,43 +13,302 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::any::type_name;
 use std::mem;
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::marker::PhantomData;
 use std::rc::Rc;
 
 use futures::{future,Future};
 
-use super::{Context,Interceptor};
+use super::{Context,Db,DispatchError,Interceptor};
+use panic;
+
+pub mod std;
+
+/// When an effect runs relative to the others of the same dispatch.
+/// `HandleEffects` performs the effects phase by phase, in push order
+/// within each phase.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum Phase {
+    /// Changes to application state, such as `Db` mutations.
+    State,
+    /// Everything else: output, files, processes. The default.
+    Io,
+    /// Dispatches of further events.
+    Dispatch,
+}
 
 pub trait Effect {
     fn action(self: Box<Self>);
+
+    /// What performing the effect would do, for dry runs and logs.
+    /// Defaults to the name of the effect's type.
+    fn describe(&self) -> String {
+        type_name::<Self>().to_string()
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Io
+    }
+
+    /// Effects of a dispatch with the same key are performed only
+    /// once, at the first of them. Keys are shared by all effect
+    /// types, so should name what the effect does.
+    fn dedup_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Perform the effect as part of a transaction, returning how to
+    /// undo it should a later effect fail, or why it failed itself, in
+    /// which case the transaction is rolled back. The default performs
+    /// `action`, always succeeds, and cannot be undone.
+    fn transact(self: Box<Self>) -> Result<Option<Compensation>, String> {
+        self.action();
+        Ok(None)
+    }
+}
+
+impl Effect for Box<Effect> {
+    fn action(self: Box<Self>) {
+        (*self).action()
+    }
+
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+
+    fn phase(&self) -> Phase {
+        (**self).phase()
+    }
+
+    fn dedup_key(&self) -> Option<String> {
+        (**self).dedup_key()
+    }
+
+    fn transact(self: Box<Self>) -> Result<Option<Compensation>, String> {
+        (*self).transact()
+    }
 }
 
-pub struct HandleEffects<E>(PhantomData<E>);
+/// Put `effects` in the order they are to be performed in, keeping
+/// only the first of those sharing a dedup key.
+fn schedule(mut effects: Effects) -> Effects {
+    effects.sort_by_key(|e| e.phase());
+    let mut keys = HashSet::new();
+    effects.retain(|e| match e.dedup_key() {
+        Some(key) => keys.insert(key),
+        None => true,
+    });
+    effects
+}
+
+/// Undoes an effect performed in a transaction that later failed.
+pub type Compensation = Box<FnOnce()>;
+
+/// Saves the state of a `Db` at the start of a transaction, returning
+/// how to put it back.
+type Stage = Rc<Fn() -> Compensation>;
+
+/// The effects returned by a handler, to be pushed onto the context.
+pub type Effects = Vec<Box<Effect>>;
+
+/// Performs the effects accumulated in the Context on the way back
+/// out of the chain, ordered by `Phase` and without duplicates.
+///
+/// In a dry run (see `EventDispatcher::dispatch_dry_run`) the effects
+/// are described into the context and dropped instead.
+pub struct HandleEffects<E> {
+    panic_safe: bool,
+    transactional: bool,
+    staged: Vec<Stage>,
+    phantom: PhantomData<E>,
+}
+
+impl<E> Default for HandleEffects<E> {
+    fn default() -> HandleEffects<E> {
+        HandleEffects::new()
+    }
+}
 
 impl<E> HandleEffects<E>
 {
     pub fn new() -> HandleEffects<E> {
-        HandleEffects(PhantomData)
+        HandleEffects { panic_safe: false, transactional: false, staged: vec![], phantom: PhantomData }
+    }
+
+    /// A `HandleEffects` that stops at the first effect that panics
+    /// and fails the dispatch with `DispatchError::Panicked` instead
+    /// of unwinding. Effects after the one that panicked are dropped
+    /// without running.
+    pub fn panic_safe() -> HandleEffects<E> {
+        HandleEffects { panic_safe: true, ..HandleEffects::new() }
+    }
+
+    /// A panic-safe `HandleEffects` that performs the effects of each
+    /// dispatch all or nothing. If an effect panics or its `transact`
+    /// fails, the effects before it are undone, latest first: the
+    /// `Db`s added with `stage` are put back as they were before the
+    /// first effect, and other effects run their compensation, if they
+    /// have one (see `compensate`). The dispatch then fails with
+    /// `DispatchError::Panicked` or `DispatchError::EffectFailed`.
+    pub fn transactional() -> HandleEffects<E> {
+        HandleEffects { panic_safe: true, transactional: true, ..HandleEffects::new() }
+    }
+
+    /// Restore `db` if a transaction fails.
+    pub fn stage<S: 'static + Clone>(mut self, db: &Db<S>) -> HandleEffects<E> {
+        let db = db.clone();
+        self.staged.push(Rc::new(move || {
+            let state = db.update();
+            let db = db.clone();
+            Box::new(move || db.restore(state)) as Compensation
+        }));
+        self
+    }
+
+    fn transact(&self, event: &'static str, effects: Effects) -> Result<(), DispatchError> {
+        // Any effect may mutate a staged `Db`, whatever its phase, so
+        // they are staged unless there is nothing to perform.
+        let mut undo: Vec<Compensation> = if effects.is_empty() {
+            vec![]
+        } else {
+            self.staged.iter().map(|stage| stage()).collect()
+        };
+        for e in effects.into_iter() {
+            let transacted = panic::guard(true, event, || e.transact())
+                .and_then(|transacted| transacted.map_err(|message| DispatchError::EffectFailed { event, message }));
+            match transacted {
+                Ok(compensation) => undo.extend(compensation),
+                Err(error) => {
+                    for compensation in undo.into_iter().rev() {
+                        if let Err(failed) = panic::guard(true, event, compensation) {
+                            error!("failed to undo an effect of {}: {}", event, failed);
+                        }
+                    }
+                    return Err(error);
+                },
+            }
+        }
+        Ok(())
     }
 }
 
 impl<E> Interceptor for HandleEffects<E>
-where E: 'static,
+where E: 'static + From<DispatchError>,
 {
     type Error = E;
 
     fn after(&self, mut context: Context<Self::Error>) -> Box<Future<Item = Context<Self::Error>,
                                                                      Error = Self::Error>> {
-        let effects = mem::replace(&mut context.effects, vec![]);
+        let effects = schedule(mem::take(&mut context.effects));
+        if let Some(ref mut described) = context.dry_run {
+            described.extend(effects.iter().map(|e| e.describe()));
+            return Box::new(future::ok(context));
+        }
+        let event = context.event_name().unwrap_or("unknown");
+        if self.transactional {
+            return match self.transact(event, effects) {
+                Ok(()) => Box::new(future::ok(context)),
+                Err(error) => Box::new(future::err(context.fail(error))),
+            };
+        }
         for e in effects.into_iter() {
-            e.action();
+            if let Err(error) = panic::guard(self.panic_safe, event, || e.action()) {
+                return Box::new(future::err(context.fail(error)));
+            }
         }
         Box::new(future::ok(context))
     }
 }
 
+/// Wrap `effect` so that, in a transaction that fails after it was
+/// performed, `undo` is called to reverse it.
+pub fn compensate<Ef, F>(effect: Ef, undo: F) -> Compensated<Ef, F>
+where Ef: Effect,
+      F: 'static + FnOnce(),
+{
+    Compensated { effect, undo }
+}
+
+pub struct Compensated<Ef, F> {
+    effect: Ef,
+    undo: F,
+}
+
+impl<Ef, F> Effect for Compensated<Ef, F>
+where Ef: 'static + Effect,
+      F: 'static + FnOnce(),
+{
+    fn action(self: Box<Self>) {
+        Box::new(self.effect).action()
+    }
+
+    fn describe(&self) -> String {
+        self.effect.describe()
+    }
+
+    fn phase(&self) -> Phase {
+        self.effect.phase()
+    }
+
+    fn dedup_key(&self) -> Option<String> {
+        self.effect.dedup_key()
+    }
+
+    fn transact(self: Box<Self>) -> Result<Option<Compensation>, String> {
+        let Compensated { effect, undo } = *self;
+        Box::new(effect).transact()?;
+        Ok(Some(Box::new(undo)))
+    }
+}
+
+/// Declare the phase or dedup key of `effect`, in place of its own.
+pub fn declare<Ef: Effect>(effect: Ef) -> Declared<Ef> {
+    Declared { effect, phase: None, key: None }
+}
+
+pub struct Declared<Ef> {
+    effect: Ef,
+    phase: Option<Phase>,
+    key: Option<String>,
+}
+
+impl<Ef> Declared<Ef> {
+    pub fn phase(mut self, phase: Phase) -> Declared<Ef> {
+        self.phase = Some(phase);
+        self
+    }
+
+    pub fn dedup<K: Into<String>>(mut self, key: K) -> Declared<Ef> {
+        self.key = Some(key.into());
+        self
+    }
+}
+
+impl<Ef: 'static + Effect> Effect for Declared<Ef> {
+    fn action(self: Box<Self>) {
+        Box::new(self.effect).action()
+    }
+
+    fn describe(&self) -> String {
+        self.effect.describe()
+    }
+
+    fn phase(&self) -> Phase {
+        self.phase.unwrap_or_else(|| self.effect.phase())
+    }
+
+    fn dedup_key(&self) -> Option<String> {
+        self.key.clone().or_else(|| self.effect.dedup_key())
+    }
+
+    fn transact(self: Box<Self>) -> Result<Option<Compensation>, String> {
+        Box::new(self.effect).transact()
+    }
+}
+
 pub struct MutateState<S, F> {
     state_ref: Option<Rc<RefCell<S>>>,
     mutate: F,
@@ -70,6 +329,14 @@ where S: 'static,
         let mut state = state_ref.borrow_mut();
         (self.mutate)(&mut state)
     }
+
+    fn describe(&self) -> String {
+        format!("mutate {}", type_name::<S>())
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::State
+    }
 }
 
 #[cfg(test)]
@@ -92,4 +359,144 @@ mod tests {
 
         assert_eq!(state.borrow().0, 10);
     }
+
+    struct Explode;
+
+    impl Effect for Explode {
+        fn action(self: Box<Self>) {
+            panic!("effect failed")
+        }
+    }
+
+    #[test]
+    fn test_panic_safe_effects_stop_at_panic() {
+        let mut context: Context<DispatchError> = Context::new(vec![]);
+        let i: HandleEffects<DispatchError> = HandleEffects::panic_safe();
+
+        let state = Rc::new(RefCell::new(State(0)));
+        context.push_effect(MutateState::new(Rc::clone(&state), |state: &mut State| state.0 = 10));
+        context.push_effect(declare(Explode).phase(Phase::State));
+        context.push_effect(MutateState::new(Rc::clone(&state), |state: &mut State| state.0 = 20));
+
+        match i.after(context).wait() {
+            Err(DispatchError::Panicked { message, .. }) => assert_eq!("effect failed", message),
+            _ => panic!("expected the effects to panic"),
+        }
+        assert_eq!(state.borrow().0, 10);
+    }
+
+    struct Noop;
+
+    impl Effect for Noop {
+        fn action(self: Box<Self>) {}
+    }
+
+    struct Push(Rc<RefCell<Vec<&'static str>>>, &'static str);
+
+    impl Effect for Push {
+        fn action(self: Box<Self>) {
+            self.0.borrow_mut().push(self.1)
+        }
+    }
+
+    #[test]
+    fn test_effects_run_by_phase_without_duplicates() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let push = |name| Push(Rc::clone(&log), name);
+        let mut context: Context<()> = Context::new(vec![]);
+        context.push_effect(declare(push("prompt")).phase(Phase::Dispatch).dedup("prompt"));
+        context.push_effect(push("print"));
+        context.push_effect(declare(push("prompt again")).phase(Phase::Dispatch).dedup("prompt"));
+        context.push_effect(declare(push("mutate")).phase(Phase::State));
+
+        HandleEffects::new().after(context).wait().unwrap();
+        assert_eq!(vec!["mutate", "print", "prompt"], *log.borrow());
+    }
+
+    #[test]
+    fn test_transactional_effects_roll_back() {
+        let db = Db::new(State(0));
+        let undone = Rc::new(RefCell::new(false));
+        let i: HandleEffects<DispatchError> = HandleEffects::transactional().stage(&db);
+
+        let mut context: Context<DispatchError> = Context::new(vec![]);
+        let flag = Rc::clone(&undone);
+        context.push_effect(db.mutate(|state: &mut State| state.0 = 10));
+        context.push_effect(compensate(Noop, move || *flag.borrow_mut() = true));
+        context.push_effect(Explode);
+        assert!(i.after(context).wait().is_err());
+        assert_eq!(0, db.borrow().0);
+        assert!(*undone.borrow());
+
+        let mut context: Context<DispatchError> = Context::new(vec![]);
+        context.push_effect(db.mutate(|state: &mut State| state.0 = 20));
+        assert!(i.after(context).wait().is_ok());
+        assert_eq!(20, db.borrow().0);
+    }
+
+    struct Refuse;
+
+    impl Effect for Refuse {
+        fn action(self: Box<Self>) {}
+
+        fn transact(self: Box<Self>) -> Result<Option<Compensation>, String> {
+            Err("refused".to_string())
+        }
+    }
+
+    #[test]
+    fn test_failed_effect_rolls_back() {
+        let db = Db::new(State(0));
+        let undone = Rc::new(RefCell::new(false));
+        let i: HandleEffects<DispatchError> = HandleEffects::transactional().stage(&db);
+
+        let mut context: Context<DispatchError> = Context::new(vec![]);
+        let flag = Rc::clone(&undone);
+        context.push_effect(db.mutate(|state: &mut State| state.0 = 10));
+        context.push_effect(compensate(Noop, move || *flag.borrow_mut() = true));
+        context.push_effect(Refuse);
+        match i.after(context).wait() {
+            Err(DispatchError::EffectFailed { message, .. }) => assert_eq!("refused", message),
+            _ => panic!("expected the effect to fail"),
+        }
+        assert_eq!(0, db.borrow().0);
+        assert!(*undone.borrow());
+    }
+
+    struct Counted(Rc<RefCell<u32>>);
+
+    impl Clone for Counted {
+        fn clone(&self) -> Counted {
+            *self.0.borrow_mut() += 1;
+            Counted(Rc::clone(&self.0))
+        }
+    }
+
+    #[test]
+    fn test_staging_skipped_without_effects() {
+        let clones = Rc::new(RefCell::new(0));
+        let db = Db::new(Counted(Rc::clone(&clones)));
+        let i: HandleEffects<DispatchError> = HandleEffects::transactional().stage(&db);
+
+        let context: Context<DispatchError> = Context::new(vec![]);
+        assert!(i.after(context).wait().is_ok());
+        assert_eq!(0, *clones.borrow());
+
+        let mut context: Context<DispatchError> = Context::new(vec![]);
+        context.push_effect(Noop);
+        assert!(i.after(context).wait().is_ok());
+        assert_eq!(1, *clones.borrow());
+    }
+
+    #[test]
+    fn test_mutation_in_another_phase_rolls_back() {
+        let db = Db::new(State(0));
+        let i: HandleEffects<DispatchError> = HandleEffects::transactional().stage(&db);
+
+        let mut context: Context<DispatchError> = Context::new(vec![]);
+        context.push_effect(declare(db.mutate(|state: &mut State| state.0 = 10)).phase(Phase::Io));
+        context.push_effect(Refuse);
+        assert!(i.after(context).wait().is_err());
+        assert_eq!(0, db.borrow().0);
+    }
 }
@@ -0,0 +1,403 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Effects for common I/O: printing, files and child processes.
+//!
+//! They are created through an `Io` coeffect, which an app injects
+//! with `AppBuilder::coeffect(Io::new())`. Injecting
+//! `Io::recording(&recorder)` instead, e.g. in tests, makes every
+//! effect it creates add its description to `recorder` rather than
+//! perform it.
+//!
+//! Failures to print or to write files are logged, or, under a
+//! transactional `HandleEffects`, fail the dispatch and roll it back.
+//! The outcome of a child process is handed to the event it
+//! dispatches.
+
+use std::cell::RefCell;
+use std::fs::{self,OpenOptions};
+use std::io::{self,Write};
+use std::path::PathBuf;
+use std::process::{Command,Output};
+use std::rc::Rc;
+use std::thread;
+
+use futures::Future;
+use futures::sync::oneshot;
+
+use {Coeffect,DispatchError,Dispatcher,Effect,NewCoeffect};
+use effects::Compensation;
+
+/// The descriptions of the effects created by a recording `Io`, in
+/// the order they were performed.
+#[derive(Clone,Default)]
+pub struct Recorder(Rc<RefCell<Vec<String>>>);
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    pub fn recorded(&self) -> Vec<String> {
+        self.0.borrow().clone()
+    }
+
+    /// Take the descriptions recorded so far, leaving none.
+    pub fn take(&self) -> Vec<String> {
+        self.0.borrow_mut().split_off(0)
+    }
+}
+
+/// Record the effect if it has a recorder, returning whether it did.
+fn record(recorder: &Option<Recorder>, effect: &Effect) -> bool {
+    match *recorder {
+        Some(ref recorder) => {
+            recorder.0.borrow_mut().push(effect.describe());
+            true
+        },
+        None => false,
+    }
+}
+
+/// Creates the effects of this module.
+#[derive(Clone,Default)]
+pub struct Io {
+    recorder: Option<Recorder>,
+}
+
+impl Io {
+    /// An `Io` whose effects are performed.
+    pub fn new() -> Io {
+        Io::default()
+    }
+
+    /// An `Io` whose effects are only recorded.
+    pub fn recording(recorder: &Recorder) -> Io {
+        Io { recorder: Some(recorder.clone()) }
+    }
+
+    pub fn print<S: Into<String>>(&self, text: S) -> Print {
+        Print { stream: Stream::Stdout, text: text.into(), recorder: self.recorder.clone() }
+    }
+
+    pub fn eprint<S: Into<String>>(&self, text: S) -> Print {
+        Print { stream: Stream::Stderr, text: text.into(), recorder: self.recorder.clone() }
+    }
+
+    /// Replace the contents of the file at `path`, creating it if
+    /// needed.
+    pub fn write_file<P, C>(&self, path: P, contents: C) -> WriteFile
+    where P: Into<PathBuf>,
+          C: Into<Vec<u8>>,
+    {
+        WriteFile { path: path.into(), contents: contents.into(), append: false, recorder: self.recorder.clone() }
+    }
+
+    /// Add to the end of the file at `path`, creating it if needed.
+    pub fn append_file<P, C>(&self, path: P, contents: C) -> WriteFile
+    where P: Into<PathBuf>,
+          C: Into<Vec<u8>>,
+    {
+        WriteFile { path: path.into(), contents: contents.into(), append: true, recorder: self.recorder.clone() }
+    }
+
+    pub fn remove_file<P: Into<PathBuf>>(&self, path: P) -> RemoveFile {
+        RemoveFile { path: path.into(), recorder: self.recorder.clone() }
+    }
+
+    /// Run `command` to completion on a thread of its own, then
+    /// dispatch the event `on_exit` makes of its exit status and
+    /// output through `dispatcher`.
+    pub fn spawn<F, Ev, E>(&self, command: Command, dispatcher: &Dispatcher<E>, on_exit: F) -> Spawn<F, E>
+    where F: 'static + FnOnce(io::Result<Output>) -> Ev,
+          Ev: 'static,
+          E: 'static + From<DispatchError>,
+    {
+        Spawn { command, dispatcher: dispatcher.clone(), on_exit, recorder: self.recorder.clone() }
+    }
+}
+
+impl Coeffect for Io {}
+
+impl NewCoeffect for Io {
+    type Instance = Io;
+
+    fn new_coeffect(&self) -> Io {
+        self.clone()
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Stream {
+    Stdout, Stderr,
+}
+
+/// Prints a line.
+pub struct Print {
+    stream: Stream,
+    text: String,
+    recorder: Option<Recorder>,
+}
+
+impl Print {
+    fn perform(&self) -> Result<(), String> {
+        let written = match self.stream {
+            Stream::Stdout => writeln!(io::stdout(), "{}", self.text),
+            Stream::Stderr => writeln!(io::stderr(), "{}", self.text),
+        };
+        written.map_err(|e| format!("failed to print to {:?}: {}", self.stream, e))
+    }
+}
+
+impl Effect for Print {
+    fn action(self: Box<Self>) {
+        if record(&self.recorder, &*self) {
+            return;
+        }
+        if let Err(e) = self.perform() {
+            error!("{}", e);
+        }
+    }
+
+    fn transact(self: Box<Self>) -> Result<Option<Compensation>, String> {
+        if record(&self.recorder, &*self) {
+            return Ok(None);
+        }
+        self.perform().map(|()| None)
+    }
+
+    fn describe(&self) -> String {
+        match self.stream {
+            Stream::Stdout => format!("print {:?}", self.text),
+            Stream::Stderr => format!("eprint {:?}", self.text),
+        }
+    }
+}
+
+/// Writes or appends to a file.
+pub struct WriteFile {
+    path: PathBuf,
+    contents: Vec<u8>,
+    append: bool,
+    recorder: Option<Recorder>,
+}
+
+impl WriteFile {
+    fn perform(&self) -> Result<(), String> {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(self.append)
+            .truncate(!self.append)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&self.contents))
+            .map_err(|e| format!("failed to write to {}: {}", self.path.display(), e))
+    }
+}
+
+impl Effect for WriteFile {
+    fn action(self: Box<Self>) {
+        if record(&self.recorder, &*self) {
+            return;
+        }
+        if let Err(e) = self.perform() {
+            error!("{}", e);
+        }
+    }
+
+    fn transact(self: Box<Self>) -> Result<Option<Compensation>, String> {
+        if record(&self.recorder, &*self) {
+            return Ok(None);
+        }
+        self.perform().map(|()| None)
+    }
+
+    fn describe(&self) -> String {
+        let verb = if self.append { "append" } else { "write" };
+        format!("{} {} bytes to {}", verb, self.contents.len(), self.path.display())
+    }
+}
+
+/// Removes a file.
+pub struct RemoveFile {
+    path: PathBuf,
+    recorder: Option<Recorder>,
+}
+
+impl RemoveFile {
+    fn perform(&self) -> Result<(), String> {
+        fs::remove_file(&self.path).map_err(|e| format!("failed to remove {}: {}", self.path.display(), e))
+    }
+}
+
+impl Effect for RemoveFile {
+    fn action(self: Box<Self>) {
+        if record(&self.recorder, &*self) {
+            return;
+        }
+        if let Err(e) = self.perform() {
+            error!("{}", e);
+        }
+    }
+
+    fn transact(self: Box<Self>) -> Result<Option<Compensation>, String> {
+        if record(&self.recorder, &*self) {
+            return Ok(None);
+        }
+        self.perform().map(|()| None)
+    }
+
+    fn describe(&self) -> String {
+        format!("remove {}", self.path.display())
+    }
+}
+
+/// Runs a child process and dispatches an event with its outcome.
+pub struct Spawn<F, E> {
+    command: Command,
+    dispatcher: Dispatcher<E>,
+    on_exit: F,
+    recorder: Option<Recorder>,
+}
+
+impl<F, Ev, E> Effect for Spawn<F, E>
+where F: 'static + FnOnce(io::Result<Output>) -> Ev,
+      Ev: 'static,
+      E: 'static + From<DispatchError>,
+{
+    fn action(self: Box<Self>) {
+        if record(&self.recorder, &*self) {
+            return;
+        }
+        let Spawn { mut command, dispatcher, on_exit, .. } = *self;
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(command.output());
+        });
+        let events = dispatcher.clone();
+        dispatcher.spawn(rx.then(move |output| {
+            let output = output.unwrap_or_else(|_| Err(io::Error::other("process thread panicked")));
+            events.dispatch(on_exit(output)).action();
+            Ok(())
+        }));
+    }
+
+    fn describe(&self) -> String {
+        format!("spawn {:?}", self.command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use tokio_core::reactor::Core;
+
+    use {App,Context};
+
+    struct Save(String);
+    struct Announce(String);
+    struct Run(Command);
+    struct Exited(io::Result<Output>);
+
+    fn app(core: &Core, io: Io, exited: &Rc<RefCell<Option<Output>>>) -> App<()> {
+        let app: App<()> = App::builder(core.handle()).coeffect(io).build();
+        app.register_fn(|Save(path), mut context: Context<()>| {
+            let io = context.coeffects.remove::<Io>().unwrap();
+            context.push_effect(io.write_file(path.clone(), "one\n"));
+            context.push_effect(io.append_file(path, "two\n"));
+            context
+        }).unwrap();
+        app.register_fn(|Announce(text), mut context: Context<()>| {
+            let io = context.coeffects.remove::<Io>().unwrap();
+            context.push_effect(io.print(text));
+            context
+        }).unwrap();
+        app.register_fn(|Run(command), mut context: Context<()>| {
+            let io = context.coeffects.remove::<Io>().unwrap();
+            let dispatcher = context.coeffects.remove::<Dispatcher<()>>().unwrap();
+            context.push_effect(io.spawn(command, &dispatcher, Exited));
+            context
+        }).unwrap();
+        let exited = Rc::clone(exited);
+        app.register_fn(move |Exited(output), context: Context<()>| {
+            *exited.borrow_mut() = output.ok();
+            context
+        }).unwrap();
+        app
+    }
+
+    /// Removes a file when dropped, whether or not the test passed.
+    struct TempFile<'a>(&'a Path);
+
+    impl<'a> Drop for TempFile<'a> {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(self.0);
+        }
+    }
+
+    #[test]
+    fn test_recording_io_performs_nothing() {
+        let mut core = Core::new().unwrap();
+        let recorder = Recorder::new();
+        let exited = Rc::new(RefCell::new(None));
+        let app = app(&core, Io::recording(&recorder), &exited);
+
+        let mut echo = Command::new("echo");
+        echo.arg("hi");
+        core.run(app.dispatch(Save("/nonexistent/notes".to_string()))).ok().unwrap();
+        core.run(app.dispatch(Announce("saved".to_string()))).ok().unwrap();
+        core.run(app.dispatch(Run(echo))).ok().unwrap();
+        core.turn(Some(Duration::from_millis(10)));
+
+        assert_eq!(vec!["write 4 bytes to /nonexistent/notes",
+                        "append 4 bytes to /nonexistent/notes",
+                        "print \"saved\"",
+                        "spawn \"echo\" \"hi\""], recorder.take());
+        assert!(exited.borrow().is_none());
+    }
+
+    #[test]
+    fn test_files_and_processes() {
+        let mut core = Core::new().unwrap();
+        let exited = Rc::new(RefCell::new(None));
+        let app = app(&core, Io::new(), &exited);
+
+        let path = env::temp_dir().join(format!("tokio-interceptor-std-{}", ::std::process::id()));
+        let _cleanup = TempFile(&path);
+        core.run(app.dispatch(Save(path.to_string_lossy().into_owned()))).ok().unwrap();
+        assert_eq!("one\ntwo\n", fs::read_to_string(&path).unwrap());
+        Box::new(Io::new().remove_file(path.clone())).action();
+        assert!(fs::metadata(&path).is_err());
+
+        // The test binary itself is the one program sure to be there.
+        let mut list = Command::new(env::current_exe().unwrap());
+        list.args(["--list", "test_files_and_processes"]);
+        core.run(app.dispatch(Run(list))).ok().unwrap();
+        for _ in 0..100 {
+            if exited.borrow().is_some() {
+                break;
+            }
+            core.turn(Some(Duration::from_millis(10)));
+        }
+        let output = exited.borrow_mut().take().unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("test_files_and_processes"));
+    }
+}
@@ -0,0 +1,95 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Failures produced by the dispatch machinery itself, rather than by
+/// an interceptor. Interceptor error types must be convertible from
+/// `DispatchError` to be dispatched.
+#[derive(Clone,Debug,PartialEq)]
+pub enum DispatchError {
+    /// The dispatch of `event` did not complete before the deadline
+    /// set by a `Timeout` interceptor.
+    TimedOut { event: &'static str, after: Duration },
+    /// The dispatch of `event` was stopped through its `CancelHandle`.
+    Cancelled { event: &'static str },
+    /// An interceptor, event handler or effect panicked while
+    /// dispatching `event`, and the panic was caught by `CatchPanic`
+    /// or a panic-safe `HandleEffects`.
+    Panicked { event: &'static str, message: String },
+    /// The handler for `event` needed a `coeffect` that no interceptor
+    /// had injected into the context.
+    MissingCoeffect { event: &'static str, coeffect: &'static str },
+    /// `event` declared access to `store` that conflicts with the
+    /// dispatch of `with` still in flight.
+    Conflict { event: &'static str, store: &'static str, with: &'static str },
+    /// An effect of `event` reported a failure from `Effect::transact`.
+    EffectFailed { event: &'static str, message: String },
+    /// `event` was dispatched without a chain registered for it.
+    Unregistered { event: &'static str },
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DispatchError::TimedOut { event, after } =>
+                write!(f, "dispatch of {} timed out after {:?}", event, after),
+            DispatchError::Cancelled { event } =>
+                write!(f, "dispatch of {} was cancelled", event),
+            DispatchError::Panicked { event, ref message } =>
+                write!(f, "dispatch of {} panicked: {}", event, message),
+            DispatchError::MissingCoeffect { event, coeffect } =>
+                write!(f, "dispatch of {} is missing coeffect {}", event, coeffect),
+            DispatchError::Conflict { event, store, with } =>
+                write!(f, "dispatch of {} conflicts with {} over {}", event, with, store),
+            DispatchError::EffectFailed { event, ref message } =>
+                write!(f, "an effect of {} failed: {}", event, message),
+            DispatchError::Unregistered { event } =>
+                write!(f, "{} is not registered", event),
+        }
+    }
+}
+
+impl Error for DispatchError {}
+
+/// Failures registering an event with an `EventDispatcher`.
+#[derive(Clone,Debug,PartialEq)]
+pub enum RegistrationError {
+    /// `event` already has a chain of interceptors registered.
+    AlreadyRegistered { event: &'static str },
+    /// `event` declared access to `store`, which the app does not have.
+    UnknownStore { event: &'static str, store: &'static str },
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegistrationError::AlreadyRegistered { event } =>
+                write!(f, "{} is already registered", event),
+            RegistrationError::UnknownStore { event, store } =>
+                write!(f, "{} accesses unknown store {}", event, store),
+        }
+    }
+}
+
+impl Error for RegistrationError {}
+
+/// Applications that don't distinguish failures can keep using `()`
+/// as their error type.
+impl From<DispatchError> for () {
+    fn from(_error: DispatchError) {}
+}
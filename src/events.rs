@@ -13,17 +13,22 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::any::TypeId;
+use std::any::{Any,TypeId,type_name};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::marker::PhantomData;
-use std::rc::Rc;
+use std::rc::{Rc,Weak};
 
 use futures::{future,Future};
 use tokio_core::reactor::Handle;
 
+use batch::{AnyEvent,DispatchAll};
+use closure::{FnHandler,IntoContextFuture};
+use observe::Observers;
 use effects::Effect;
-use super::{Coeffect,Context,Dispatched,Interceptor,NewCoeffect};
+use super::{CancelHandle,Coeffect,Context,Db,DispatchError,Dispatched,EventMeta,Interceptor,LagPolicy,
+            Lifecycle,NewCoeffect,Observed,Phase,RegistrationError,Subscription};
 
 pub trait Event<E> {
     fn handle(self: Box<Self>, context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>>;
@@ -37,10 +42,16 @@ impl<T: Event<E>, E> EventInterceptor<T, E> {
     }
 }
 
-impl<E: 'static, T: Event<E>> Interceptor for EventInterceptor<T, E> {
+impl<E: 'static, T: 'static + Event<E>> Interceptor for EventInterceptor<T, E> {
     type Error = E;
-    fn before(&self, context: Context<Self::Error>) -> Box<Future<Item = Context<Self::Error>,
-                                                                  Error = Self::Error>> {
+
+    fn name(&self) -> &str {
+        type_name::<T>()
+    }
+
+    fn before(&self, mut context: Context<Self::Error>) -> Box<Future<Item = Context<Self::Error>,
+                                                                      Error = Self::Error>> {
+        context.handled = true;
         let mut cell = self.0.borrow_mut();
         let event = cell.take();
         (Box::new(event.unwrap())).handle(context)
@@ -49,20 +60,103 @@ impl<E: 'static, T: Event<E>> Interceptor for EventInterceptor<T, E> {
 
 pub struct Dispatcher<E>{
     handle: Handle,
-    dispatcher: Rc<RefCell<EventDispatcher<E>>>,
+    dispatcher: EventDispatcher<E>,
+    lifecycle: Lifecycle,
+    parent: Option<EventMeta>,
 }
 
 impl<E> Dispatcher<E>
 where E: 'static,
 {
-    pub fn new(handle: &Handle, dispatcher: &Rc<RefCell<EventDispatcher<E>>>) -> Dispatcher<E> {
-        Dispatcher { handle: handle.clone(), dispatcher: Rc::clone(dispatcher) }
+    pub fn new(handle: &Handle, dispatcher: &EventDispatcher<E>) -> Dispatcher<E> {
+        Dispatcher::with_lifecycle(handle, dispatcher, dispatcher.lifecycle())
+    }
+
+    /// A `Dispatcher` whose dispatches are tracked by `lifecycle`, so
+    /// that a shutting down `App` waits for them.
+    pub fn with_lifecycle(handle: &Handle, dispatcher: &EventDispatcher<E>,
+                          lifecycle: &Lifecycle) -> Dispatcher<E> {
+        Dispatcher {
+            handle: handle.clone(),
+            dispatcher: dispatcher.clone(),
+            lifecycle: lifecycle.clone(),
+            parent: None,
+        }
+    }
+
+    /// A `Dispatcher` whose dispatches are recorded as caused by the
+    /// dispatch described by `parent`.
+    pub fn caused_by(&self, parent: &EventMeta) -> Dispatcher<E> {
+        let mut dispatcher = self.clone();
+        dispatcher.parent = Some(parent.clone());
+        dispatcher
+    }
+
+    /// The dispatch that this dispatcher's dispatches are caused by.
+    pub fn parent(&self) -> Option<&EventMeta> {
+        self.parent.as_ref()
     }
 
     pub fn dispatch<Ev>(&self, event: Ev) -> Box<Effect>
-    where Ev: 'static + Event<E>
+    where Ev: 'static,
+          E: From<DispatchError>,
+    {
+        let mut dispatch = Dispatch::new(event, &self.handle, &self.dispatcher).tracked_by(&self.lifecycle);
+        if let Some(ref parent) = self.parent {
+            dispatch = dispatch.caused_by(parent);
+        }
+        Box::new(dispatch)
+    }
+
+    /// An effect that dispatches `events` in order, each once the
+    /// previous one has completed. The batch stops at the first failure.
+    /// Events of different types can be collected with `events!`.
+    pub fn dispatch_all<I>(&self, events: I) -> Box<Effect>
+    where I: IntoIterator<Item = AnyEvent<E>>,
+          E: From<DispatchError>,
+    {
+        Box::new(self.batch(events))
+    }
+
+    /// Like `dispatch_all`, but if any event fails `db` is restored to
+    /// its state from before the batch started. The batch needs `db` to
+    /// itself until it completes; see `DispatchAll`.
+    pub fn dispatch_all_atomic<I, S>(&self, events: I, db: &Db<S>) -> Box<Effect>
+    where I: IntoIterator<Item = AnyEvent<E>>,
+          S: 'static + Clone,
+          E: From<DispatchError>,
+    {
+        Box::new(self.batch(events).atomic(db))
+    }
+
+    fn batch<I>(&self, events: I) -> DispatchAll<E>
+    where I: IntoIterator<Item = AnyEvent<E>>,
+          E: From<DispatchError>,
+    {
+        let mut batch = DispatchAll::new(events, &self.handle, &self.dispatcher).tracked_by(&self.lifecycle);
+        if let Some(ref parent) = self.parent {
+            batch = batch.caused_by(parent);
+        }
+        batch
+    }
+
+    /// The `EventDispatcher` this dispatches through, for registering
+    /// events from inside handlers and effects.
+    pub fn event_dispatcher(&self) -> &EventDispatcher<E> {
+        &self.dispatcher
+    }
+
+    /// An effect that shuts the running `App` down with `exit_code`.
+    pub fn shutdown(&self, exit_code: i32) -> Box<Effect> {
+        Box::new(self.lifecycle.shutdown(exit_code))
+    }
+
+    /// Run `future` on the reactor, tracked like this dispatcher's
+    /// dispatches. For effects that complete later.
+    pub fn spawn<F>(&self, future: F)
+    where F: 'static + Future<Item = (), Error = ()>,
     {
-        Box::new(Dispatch::new(event, &self.handle, &self.dispatcher))
+        self.handle.spawn(self.lifecycle.track(future));
     }
 }
 
@@ -70,7 +164,12 @@ impl<E> Clone for Dispatcher<E>
 where E: 'static,
 {
     fn clone(&self) -> Dispatcher<E> {
-        Dispatcher::new(&self.handle, &self.dispatcher)
+        Dispatcher {
+            handle: self.handle.clone(),
+            dispatcher: self.dispatcher.clone(),
+            lifecycle: self.lifecycle.clone(),
+            parent: self.parent.clone(),
+        }
     }
 }
 
@@ -84,63 +183,512 @@ impl<E: 'static> NewCoeffect for Dispatcher<E> {
     }
 }
 
+/// Injects a `Dispatcher` whose dispatches are caused by the event
+/// being dispatched, so that their `EventMeta` records it as their
+/// parent.
+pub struct InjectDispatcher<E>(Dispatcher<E>);
+
+impl<E: 'static> InjectDispatcher<E> {
+    pub fn new(dispatcher: Dispatcher<E>) -> InjectDispatcher<E> {
+        InjectDispatcher(dispatcher)
+    }
+}
+
+impl<E: 'static> Interceptor for InjectDispatcher<E> {
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        let dispatcher = match context.meta() {
+            Some(meta) => self.0.caused_by(meta),
+            None => self.0.clone(),
+        };
+        context.coeffects.insert(dispatcher);
+        Box::new(future::ok(context))
+    }
+}
+
 pub struct Dispatch<E, Err> {
     event: E,
     handle: Handle,
-    dispatcher: Rc<RefCell<EventDispatcher<Err>>>,
+    dispatcher: EventDispatcher<Err>,
+    lifecycle: Option<Lifecycle>,
+    parent: Option<EventMeta>,
 }
 
 impl<E, Err> Dispatch<E, Err>
-where E: 'static + Event<Err>,
+where E: 'static,
       Err: 'static,
 {
-    pub fn new(event: E, handle: &Handle, dispatcher: &Rc<RefCell<EventDispatcher<Err>>>) -> Dispatch<E, Err> {
+    pub fn new(event: E, handle: &Handle, dispatcher: &EventDispatcher<Err>) -> Dispatch<E, Err> {
         Dispatch {
             event,
             handle: handle.clone(),
-            dispatcher: Rc::clone(dispatcher)
+            dispatcher: dispatcher.clone(),
+            lifecycle: None,
+            parent: None,
         }
     }
 
-    pub fn into_parts(self) -> (E, Handle, Rc<RefCell<EventDispatcher<Err>>>) {
+    pub fn tracked_by(mut self, lifecycle: &Lifecycle) -> Dispatch<E, Err> {
+        self.lifecycle = Some(lifecycle.clone());
+        self
+    }
+
+    pub fn caused_by(mut self, parent: &EventMeta) -> Dispatch<E, Err> {
+        self.parent = Some(parent.clone());
+        self
+    }
+
+    pub fn into_parts(self) -> (E, Handle, EventDispatcher<Err>) {
         (self.event, self.handle, self.dispatcher)
     }
 }
 
 impl<E, Err> Effect for Dispatch<E, Err>
-where E: 'static + Event<Err>,
-      Err: 'static,
+where E: 'static,
+      Err: 'static + From<DispatchError>,
 {
-    fn action(self: Box<Self>) {
+    fn action(mut self: Box<Self>) {
+        let lifecycle = self.lifecycle.take();
+        let parent = self.parent.take();
         let (event, handle, dispatcher) = self.into_parts();
-        handle.spawn(dispatcher.borrow().dispatch(event).map(|_| ()).map_err(|_| ()));
+        let dispatched = dispatcher.dispatched(event, parent.as_ref(), None, false).map(|_| ()).map_err(|_| ());
+        match lifecycle {
+            Some(lifecycle) => handle.spawn(lifecycle.track(dispatched)),
+            None => handle.spawn(dispatched),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("dispatch {}", type_name::<E>())
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Dispatch
     }
 }
 
+/// The table of interceptor chains, keyed by event type.
+///
+/// `EventDispatcher` is a cheap handle onto a shared table, so clones
+/// register into and dispatch from the same set of events. The table
+/// is only borrowed for the duration of each method call, never while
+/// interceptors run: a dispatch works on a snapshot of the chain taken
+/// when it started, and registering from inside a handler or effect is
+/// always safe. The same goes for unregistering and replacing: a
+/// dispatch already in flight finishes on the chain it started with.
 pub struct EventDispatcher<E> {
-    event_handlers: HashMap<TypeId, Vec<Rc<Box<Interceptor<Error = E>>>>>,
+    event_handlers: Rc<RefCell<HashMap<TypeId, Chain<E>>>>,
+    inspectors: Rc<RefCell<HashMap<TypeId, Vec<Inspector<E>>>>>,
+    observers: Observers,
+    lifecycle: Lifecycle,
+}
+
+/// Looks at a dispatched event, boxed as `Any`, and the context made
+/// for it; see `EventDispatcher::inspect`.
+type Inspector<E> = Rc<Fn(&Any, &mut Context<E>)>;
+
+/// Builds the interceptor that handles one dispatched event, given the
+/// event boxed as `Any`.
+type Handler<E> = Rc<Fn(Box<Any>) -> Box<Interceptor<Error = E>>>;
+
+struct Registered<E> {
+    interceptors: Vec<Rc<Box<Interceptor<Error = E>>>>,
+    handler: Handler<E>,
+}
+
+type Chain<E> = Rc<Registered<E>>;
+
+/// Takes a chain out of the table, copying it only if a guard or an
+/// in-flight lookup still shares it.
+fn into_interceptors<E>(chain: Chain<E>) -> Vec<Rc<Box<Interceptor<Error = E>>>> {
+    match Rc::try_unwrap(chain) {
+        Ok(registered) => registered.interceptors,
+        Err(chain) => chain.interceptors.clone(),
+    }
+}
+
+fn downcast<Ev: 'static>(event: Box<Any>) -> Ev {
+    match event.downcast::<Ev>() {
+        Ok(event) => *event,
+        Err(_) => unreachable!("event handlers are keyed by event type"),
+    }
+}
+
+fn event_handler<Ev, E>() -> Handler<E>
+where Ev: 'static + Event<E>,
+      E: 'static,
+{
+    Rc::new(|event| Box::new(EventInterceptor::new(downcast::<Ev>(event))))
+}
+
+fn fn_handler<Ev, F, R, E>(f: F) -> Handler<E>
+where Ev: 'static,
+      F: 'static + Fn(Ev, Context<E>) -> R,
+      R: IntoContextFuture<E>,
+      E: 'static,
+{
+    let f = Rc::new(f);
+    Rc::new(move |event| Box::new(FnHandler::new(downcast::<Ev>(event), Rc::clone(&f))))
+}
+
+/// Keeps an event registered for as long as it is alive; dropping it
+/// unregisters the event. Returned by `EventDispatcher::register_scoped`.
+///
+/// If the event has been unregistered or replaced in the meantime,
+/// dropping the guard leaves the table alone.
+pub struct Registration<E> {
+    event_handlers: Weak<RefCell<HashMap<TypeId, Chain<E>>>>,
+    event: TypeId,
+    chain: Weak<Registered<E>>,
+}
+
+impl<E> Registration<E> {
+    /// Keep the event registered after the guard is gone.
+    pub fn forget(mut self) {
+        self.chain = Weak::new();
+    }
+}
+
+impl<E> Drop for Registration<E> {
+    fn drop(&mut self) {
+        let event_handlers = match self.event_handlers.upgrade() {
+            Some(event_handlers) => event_handlers,
+            None => return,
+        };
+        let removed = {
+            let mut handlers = event_handlers.borrow_mut();
+            let ours = match handlers.get(&self.event) {
+                Some(chain) => Weak::ptr_eq(&self.chain, &Rc::downgrade(chain)),
+                None => false,
+            };
+            if ours { handlers.remove(&self.event) } else { None }
+        };
+        // Interceptors may do anything when dropped, including touching
+        // the table, so they go only once it is no longer borrowed.
+        drop(removed);
+    }
+}
+
+impl<E> Clone for EventDispatcher<E> {
+    fn clone(&self) -> EventDispatcher<E> {
+        EventDispatcher {
+            event_handlers: Rc::clone(&self.event_handlers),
+            inspectors: Rc::clone(&self.inspectors),
+            observers: self.observers.clone(),
+            lifecycle: self.lifecycle.clone(),
+        }
+    }
+}
+
+impl<E: 'static> Default for EventDispatcher<E> {
+    fn default() -> EventDispatcher<E> {
+        EventDispatcher::new()
+    }
 }
 
 impl<E: 'static> EventDispatcher<E> {
     pub fn new() -> EventDispatcher<E> {
         EventDispatcher {
-            event_handlers: HashMap::new(),
+            event_handlers: Rc::new(RefCell::new(HashMap::new())),
+            inspectors: Rc::new(RefCell::new(HashMap::new())),
+            observers: Observers::new(),
+            lifecycle: Lifecycle::new(),
+        }
+    }
+
+    /// The run state shared by everything dispatching through this
+    /// `EventDispatcher`.
+    pub fn lifecycle(&self) -> &Lifecycle {
+        &self.lifecycle
+    }
+
+    /// Register the chain of interceptors to run for `Ev`. Fails if
+    /// `Ev` already has a chain registered.
+    pub fn register_event<Ev: 'static + Event<E>>(&self, interceptors: Vec<Box<Interceptor<Error = E>>>)
+                                                  -> Result<(), RegistrationError> {
+        self.register::<Ev>(interceptors, event_handler::<Ev, E>())
+    }
+
+    /// Register `f` as the handler for `Ev`, run after `interceptors`.
+    /// `Ev` needs no `Event` impl; `f` may return the `Context` or a
+    /// future of it.
+    pub fn register_fn<Ev, R>(&self, interceptors: Vec<Box<Interceptor<Error = E>>>,
+                              f: impl 'static + Fn(Ev, Context<E>) -> R) -> Result<(), RegistrationError>
+    where Ev: 'static,
+          R: IntoContextFuture<E>,
+    {
+        self.register::<Ev>(interceptors, fn_handler(f))
+    }
+
+    fn register<Ev: 'static>(&self, interceptors: Vec<Box<Interceptor<Error = E>>>, handler: Handler<E>)
+                             -> Result<(), RegistrationError> {
+        match self.event_handlers.borrow_mut().entry(TypeId::of::<Ev>()) {
+            Entry::Occupied(_) => Err(RegistrationError::AlreadyRegistered { event: type_name::<Ev>() }),
+            Entry::Vacant(entry) => {
+                let interceptors = interceptors.into_iter().map(Rc::new).collect();
+                entry.insert(Rc::new(Registered { interceptors, handler }));
+                Ok(())
+            },
+        }
+    }
+
+    /// Like `register_event`, but `Ev` stays registered only until the
+    /// returned `Registration` is dropped.
+    pub fn register_scoped<Ev: 'static + Event<E>>(&self, interceptors: Vec<Box<Interceptor<Error = E>>>)
+                                                   -> Result<Registration<E>, RegistrationError> {
+        self.register_event::<Ev>(interceptors)?;
+        let chain = self.event_handlers.borrow().get(&TypeId::of::<Ev>()).map(Rc::downgrade);
+        Ok(Registration {
+            event_handlers: Rc::downgrade(&self.event_handlers),
+            event: TypeId::of::<Ev>(),
+            chain: chain.unwrap_or_default(),
+        })
+    }
+
+    /// Remove the chain registered for `Ev`, returning it. Dispatches
+    /// of `Ev` already in flight are unaffected; later ones fail with
+    /// `DispatchError::Unregistered`.
+    pub fn unregister_event<Ev: 'static>(&self) -> Option<Vec<Rc<Box<Interceptor<Error = E>>>>> {
+        let removed = self.event_handlers.borrow_mut().remove(&TypeId::of::<Ev>());
+        removed.map(into_interceptors)
+    }
+
+    /// Register `interceptors` for `Ev` whether or not it already has a
+    /// chain, returning the chain it replaced.
+    pub fn replace_event<Ev: 'static + Event<E>>(&self, interceptors: Vec<Box<Interceptor<Error = E>>>)
+                                                 -> Option<Vec<Rc<Box<Interceptor<Error = E>>>>> {
+        let interceptors = interceptors.into_iter().map(Rc::new).collect();
+        let chain = Rc::new(Registered { interceptors, handler: event_handler::<Ev, E>() });
+        let replaced = self.event_handlers.borrow_mut().insert(TypeId::of::<Ev>(), chain);
+        replaced.map(into_interceptors)
+    }
+
+    pub fn is_registered<Ev: 'static>(&self) -> bool {
+        self.event_handlers.borrow().contains_key(&TypeId::of::<Ev>())
+    }
+
+    fn chain<Ev: 'static>(&self) -> Option<Chain<E>> {
+        self.event_handlers.borrow().get(&TypeId::of::<Ev>()).cloned()
+    }
+
+    /// Describe the chain of interceptors that dispatching an `Ev`
+    /// will run, ending with the event itself. Returns `None` if `Ev`
+    /// has not been registered.
+    pub fn describe<Ev: 'static>(&self) -> Option<Vec<String>> {
+        self.chain::<Ev>().map(|chain| {
+            let mut names: Vec<String> = chain.interceptors.iter().map(|i| i.name().to_string()).collect();
+            names.push(type_name::<Ev>().to_string());
+            names
+        })
+    }
+
+    pub fn dispatch<Ev: 'static>(&self, event: Ev) -> impl Future<Item = Context<E>, Error = E>
+    where E: From<DispatchError>
+    {
+        self.dispatched(event, None, None, false)
+    }
+
+    /// Dispatch `event` through its chain without performing its
+    /// effects: `HandleEffects` describes them into the resulting
+    /// context instead, see `Context::dry_run`. Interceptors and
+    /// handlers still run, so anything they do directly still
+    /// happens, unless they check `Context::dry_run` themselves, as an
+    /// `App`'s effect handlers do.
+    pub fn dispatch_dry_run<Ev: 'static>(&self, event: Ev) -> impl Future<Item = Context<E>, Error = E>
+    where E: From<DispatchError>
+    {
+        self.dispatched(event, None, None, true)
+    }
+
+    /// Dispatch `event` as caused by the dispatch described by
+    /// `parent`.
+    pub fn dispatch_caused_by<Ev>(&self, event: Ev, parent: &EventMeta) -> impl Future<Item = Context<E>, Error = E>
+    where Ev: 'static,
+          E: From<DispatchError>,
+    {
+        self.dispatched(event, Some(parent), None, false)
+    }
+
+    /// Dispatch `event`, returning a `CancelHandle` that can stop the
+    /// dispatch at the next interceptor boundary.
+    pub fn dispatch_cancellable<Ev>(&self, event: Ev) -> (impl Future<Item = Context<E>, Error = E>, CancelHandle)
+    where Ev: 'static,
+          E: From<DispatchError>,
+    {
+        let cancel = CancelHandle::new();
+        (self.dispatched(event, None, Some(&cancel), false), cancel)
+    }
+
+    /// Call `f` with every dispatched `Ev` and the context made for
+    /// it, before the first interceptor runs. For tools that need to
+    /// see the events themselves, such as debuggers; `f` may add
+    /// coeffects for them to pick up later in the chain.
+    pub fn inspect<Ev, F>(&self, f: F)
+    where Ev: 'static,
+          F: 'static + Fn(&Ev, &mut Context<E>),
+    {
+        let inspector = Rc::new(move |event: &Any, context: &mut Context<E>| {
+            if let Some(event) = event.downcast_ref::<Ev>() {
+                f(event, context);
+            }
+        });
+        self.inspectors.borrow_mut().entry(TypeId::of::<Ev>()).or_default().push(inspector);
+    }
+
+    /// Observe every completed dispatch of `Ev`. At most `capacity`
+    /// observations are buffered; `policy` decides what happens to
+    /// the rest.
+    pub fn subscribe<Ev: 'static>(&self, capacity: usize, policy: LagPolicy) -> Subscription {
+        self.observers.subscribe(Some(TypeId::of::<Ev>()), capacity, policy)
+    }
+
+    /// Observe every completed dispatch, whatever its event.
+    pub fn subscribe_all(&self, capacity: usize, policy: LagPolicy) -> Subscription {
+        self.observers.subscribe(None, capacity, policy)
+    }
+
+    fn dispatched<Ev: 'static>(&self, event: Ev, parent: Option<&EventMeta>,
+                               cancel: Option<&CancelHandle>, dry_run: bool) -> Observed<Dispatched<E>>
+    where E: From<DispatchError>,
+    {
+        let meta = match parent {
+            Some(parent) => parent.child(type_name::<Ev>()),
+            None => EventMeta::root(type_name::<Ev>()),
+        };
+        let mut context = Context::new(vec![]);
+        context.meta = Some(meta.clone());
+        let inspectors = self.inspectors.borrow().get(&TypeId::of::<Ev>()).cloned();
+        for inspect in inspectors.unwrap_or_default() {
+            inspect(&event, &mut context);
+        }
+        if dry_run {
+            context.dry_run = Some(vec![]);
+        }
+        let mut dispatched = match self.chain::<Ev>() {
+            Some(chain) => {
+                for interceptor in chain.interceptors.iter() {
+                    context.queue.push_back(Rc::clone(interceptor));
+                }
+                context.queue.push_back(Rc::new((chain.handler)(Box::new(event))));
+                Dispatched::new(&meta, Box::new(future::ok(context)))
+            },
+            None => {
+                let error = DispatchError::Unregistered { event: type_name::<Ev>() };
+                warn!("{}", error);
+                let dispatched = Dispatched::new(&meta, Box::new(future::err(E::from(error.clone()))));
+                *dispatched.failure.borrow_mut() = Some(error);
+                dispatched
+            },
+        };
+        if let Some(cancel) = cancel {
+            dispatched = dispatched.with_cancel(cancel);
+        }
+        let failure = Rc::clone(&dispatched.failure);
+        self.observers.observe(TypeId::of::<Ev>(), meta, failure, dispatched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    struct Late(Rc<Cell<bool>>);
+
+    impl Event<()> for Late {
+        fn handle(self: Box<Self>, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            self.0.set(true);
+            Box::new(future::ok(context))
+        }
+    }
+
+    struct RegisterLate(EventDispatcher<()>, Rc<Cell<bool>>);
+
+    impl Event<()> for RegisterLate {
+        fn handle(self: Box<Self>, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            let RegisterLate(dispatcher, called) = *self;
+            dispatcher.register_event::<Late>(vec![]).unwrap();
+            dispatcher.dispatch(Late(called)).wait().ok().unwrap();
+            Box::new(future::ok(context))
         }
     }
 
-    pub fn register_event<Ev: 'static + Event<E>>(&mut self, interceptors: Vec<Box<Interceptor<Error = E>>>) {
-        self.event_handlers.insert(TypeId::of::<Ev>(),
-                                   interceptors.into_iter().map(|i| Rc::new(i)).collect());
+    #[test]
+    fn test_register_twice_fails() {
+        let dispatcher: EventDispatcher<()> = EventDispatcher::new();
+        assert_eq!(Ok(()), dispatcher.register_event::<Late>(vec![]));
+        assert_eq!(Err(RegistrationError::AlreadyRegistered { event: type_name::<Late>() }),
+                   dispatcher.register_event::<Late>(vec![]));
+    }
+
+    #[test]
+    fn test_register_and_dispatch_from_handler() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.register_event::<RegisterLate>(vec![]).unwrap();
+
+        let called = Rc::new(Cell::new(false));
+        dispatcher.dispatch(RegisterLate(dispatcher.clone(), Rc::clone(&called))).wait().ok().unwrap();
+
+        assert!(called.get());
+        assert!(dispatcher.is_registered::<Late>());
     }
 
-    pub fn dispatch<Ev: 'static + Event<E>>(&self, event: Ev) -> impl Future<Item = Context<E>> {
-        if let Some(interceptors) = self.event_handlers.get(&TypeId::of::<Ev>()) {
-            let mut interceptors: Vec<Rc<Box<Interceptor<Error = E>>>> = interceptors.iter().map(Rc::clone).collect();
-            interceptors.push(Rc::new(Box::new(EventInterceptor::new(event)) as Box<Interceptor<Error = E>>));
-            let mut context = Context::new(interceptors);
-            Dispatched::new(Box::new(future::ok(context)))
-        } else {
-            Dispatched::new(Box::new(future::ok(Context::new(vec![]))))
+    struct Named(&'static str);
+
+    impl Interceptor for Named {
+        type Error = ();
+
+        fn name(&self) -> &str {
+            self.0
         }
     }
+
+    #[test]
+    fn test_unregister_and_replace() {
+        let dispatcher: EventDispatcher<()> = EventDispatcher::new();
+        assert!(dispatcher.unregister_event::<Late>().is_none());
+        assert!(dispatcher.replace_event::<Late>(vec![Box::new(Named("first"))]).is_none());
+
+        let replaced = dispatcher.replace_event::<Late>(vec![Box::new(Named("second"))]).unwrap();
+        assert_eq!(vec!["first"], replaced.iter().map(|i| i.name()).collect::<Vec<_>>());
+
+        let removed = dispatcher.unregister_event::<Late>().unwrap();
+        assert_eq!(vec!["second"], removed.iter().map(|i| i.name()).collect::<Vec<_>>());
+        assert!(!dispatcher.is_registered::<Late>());
+    }
+
+    #[test]
+    fn test_in_flight_dispatch_keeps_its_chain() {
+        let dispatcher: EventDispatcher<()> = EventDispatcher::new();
+        dispatcher.register_event::<Late>(vec![Box::new(Named("first"))]).unwrap();
+
+        let called = Rc::new(Cell::new(false));
+        let dispatched = dispatcher.dispatch(Late(Rc::clone(&called)));
+        dispatcher.unregister_event::<Late>();
+
+        let context = dispatched.wait().ok().unwrap();
+        assert!(called.get());
+        assert!(context.executed().contains(&"first"));
+    }
+
+    #[test]
+    fn test_registration_guard() {
+        let dispatcher: EventDispatcher<()> = EventDispatcher::new();
+        let registration = dispatcher.register_scoped::<Late>(vec![]).unwrap();
+        assert!(dispatcher.is_registered::<Late>());
+        drop(registration);
+        assert!(!dispatcher.is_registered::<Late>());
+
+        let registration = dispatcher.register_scoped::<Late>(vec![]).unwrap();
+        dispatcher.replace_event::<Late>(vec![Box::new(Named("replacement"))]);
+        drop(registration);
+        assert_eq!(Some(vec!["replacement".to_string(), type_name::<Late>().to_string()]),
+                   dispatcher.describe::<Late>());
+
+        dispatcher.unregister_event::<Late>();
+        dispatcher.register_scoped::<Late>(vec![]).unwrap().forget();
+        assert!(dispatcher.is_registered::<Late>());
+    }
 }
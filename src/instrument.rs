@@ -0,0 +1,322 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Instrumentation for `Dispatched`.
+//!
+//! With the `tracing` feature enabled every dispatch gets one
+//! `dispatch` span, shared with the rest of its chain when an
+//! `around` interceptor runs it, and every `before`/`after` call gets a child
+//! `interceptor` span recording the interceptor's name, how long its
+//! future took to resolve and whether it succeeded. The `tracing`
+//! dependency is built with its `log` feature, so without a tracing
+//! subscriber installed the same information is emitted as `log`
+//! records. Without the feature all of this compiles to nothing.
+//!
+//! Independently of the feature, a `CallHook` left in the context is
+//! told of every call to an interceptor made after it.
+
+use std::rc::Rc;
+use std::time::{Duration,Instant};
+
+pub use self::imp::{CallSpan,DispatchSpan};
+
+/// Told of each call to an interceptor once its future resolves or
+/// fails: the interceptor's name, the phase, how long the call took
+/// and how many effects it took out of the context.
+pub type CallHook = Rc<Fn(&str, &'static str, Duration, usize)>;
+
+/// A call to an interceptor being timed for a `CallHook`.
+pub struct Timing {
+    hook: CallHook,
+    interceptor: String,
+    phase: &'static str,
+    started: Instant,
+    effects: usize,
+}
+
+impl Timing {
+    /// Start timing a call made with `effects` effects in the context.
+    pub fn start(hook: &CallHook, interceptor: &str, phase: &'static str, effects: usize) -> Timing {
+        Timing {
+            hook: Rc::clone(hook),
+            interceptor: interceptor.to_string(),
+            phase,
+            started: Instant::now(),
+            effects,
+        }
+    }
+
+    /// The call finished leaving `effects` effects in the context.
+    pub fn finish(self, effects: usize) {
+        (self.hook)(&self.interceptor, self.phase, self.started.elapsed(), self.effects.saturating_sub(effects));
+    }
+
+    /// The call failed, taking no effects with it.
+    pub fn fail(self) {
+        let effects = self.effects;
+        self.finish(effects);
+    }
+}
+
+/// Whether a dispatch, or a call to an interceptor, succeeded.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Outcome {
+    Ok, Err,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Outcome::Ok => "ok",
+            Outcome::Err => "error",
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use std::time::Instant;
+
+    use tracing::{event,span,Level,Span};
+    use tracing::field::Empty;
+    use tracing::span::EnteredSpan;
+
+    use meta::EventMeta;
+    use super::Outcome;
+
+    #[derive(Clone)]
+    pub struct DispatchSpan {
+        span: Span,
+        started: Instant,
+        /// Whether this is the dispatch's own span rather than one
+        /// shared with the rest of its chain run by an `around`.
+        owned: bool,
+    }
+
+    impl DispatchSpan {
+        pub fn new(meta: &EventMeta) -> DispatchSpan {
+            let span = span!(Level::DEBUG, "dispatch", event = meta.event,
+                             id = meta.id.as_u64(), parent = Empty, root = meta.root.as_u64(),
+                             elapsed_us = Empty, outcome = Empty);
+            if let Some(parent) = meta.parent {
+                span.record("parent", parent.as_u64());
+            }
+            DispatchSpan { span, started: Instant::now(), owned: true }
+        }
+
+        /// The same span, for the rest of the chain run by an
+        /// `around`. Finishing it is left to the dispatch.
+        pub fn nested(&self) -> DispatchSpan {
+            DispatchSpan { owned: false, ..self.clone() }
+        }
+
+        pub fn enter(&self) -> EnteredSpan {
+            self.span.clone().entered()
+        }
+
+        pub fn call(&self, interceptor: &str, phase: &'static str) -> CallSpan {
+            CallSpan {
+                span: span!(parent: &self.span, Level::TRACE, "interceptor",
+                            name = interceptor, phase = phase,
+                            elapsed_us = Empty, outcome = Empty),
+                started: Instant::now(),
+            }
+        }
+
+        pub fn finish(&self, outcome: Outcome) {
+            if !self.owned {
+                return;
+            }
+            let elapsed = self.started.elapsed();
+            self.span.record("elapsed_us", elapsed.as_micros() as u64);
+            self.span.record("outcome", outcome.as_str());
+            event!(parent: &self.span, Level::DEBUG,
+                   elapsed_us = elapsed.as_micros() as u64,
+                   outcome = outcome.as_str(),
+                   "dispatch finished");
+        }
+    }
+
+    pub struct CallSpan {
+        span: Span,
+        started: Instant,
+    }
+
+    impl CallSpan {
+        pub fn enter(&self) -> EnteredSpan {
+            self.span.clone().entered()
+        }
+
+        pub fn finish(self, outcome: Outcome) {
+            let elapsed = self.started.elapsed();
+            self.span.record("elapsed_us", elapsed.as_micros() as u64);
+            self.span.record("outcome", outcome.as_str());
+            event!(parent: &self.span, Level::TRACE,
+                   elapsed_us = elapsed.as_micros() as u64,
+                   outcome = outcome.as_str(),
+                   "interceptor finished");
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use meta::EventMeta;
+    use super::Outcome;
+
+    pub struct Entered;
+
+    #[derive(Clone)]
+    pub struct DispatchSpan;
+
+    impl DispatchSpan {
+        pub fn new(_meta: &EventMeta) -> DispatchSpan {
+            DispatchSpan
+        }
+
+        pub fn nested(&self) -> DispatchSpan {
+            DispatchSpan
+        }
+
+        pub fn enter(&self) -> Entered {
+            Entered
+        }
+
+        pub fn call(&self, _interceptor: &str, _phase: &'static str) -> CallSpan {
+            CallSpan
+        }
+
+        pub fn finish(&self, _outcome: Outcome) {}
+    }
+
+    pub struct CallSpan;
+
+    impl CallSpan {
+        pub fn enter(&self) -> Entered {
+            Entered
+        }
+
+        pub fn finish(self, _outcome: Outcome) {}
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::fmt;
+    use std::sync::{Arc,Mutex};
+    use std::sync::atomic::{AtomicUsize,Ordering};
+
+    use futures::{future,Future};
+    use tracing::{Metadata,Subscriber};
+    use tracing::field::{Field,Visit};
+    use tracing::span::{Attributes,Id,Record};
+    use tracing::subscriber;
+
+    use {Context,Event,EventDispatcher,Interceptor,Next,around_fn};
+
+    #[derive(Default)]
+    struct Recorder {
+        next_id: AtomicUsize,
+        spans: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct Names<'a>(&'a mut Vec<String>);
+
+    impl<'a> Visit for Names<'a> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "name" || field.name() == "phase" {
+                self.0.push(value.to_string());
+            }
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &fmt::Debug) {}
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut fields = vec![span.metadata().name().to_string()];
+            span.record(&mut Names(&mut fields));
+            self.spans.lock().unwrap().push(fields.join(" "));
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &tracing::Event) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    struct Noop;
+
+    struct Ping;
+
+    impl Event<()> for Ping {
+        fn handle(self: Box<Self>, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            Box::new(future::ok(context))
+        }
+    }
+
+    impl Interceptor for Noop {
+        type Error = ();
+
+        fn name(&self) -> &str {
+            "noop"
+        }
+    }
+
+    #[test]
+    fn test_spans_per_interceptor_call() {
+        let recorder = Recorder::default();
+        let spans = Arc::clone(&recorder.spans);
+        let app = EventDispatcher::new();
+        app.register_event::<Ping>(vec![Box::new(Noop)]).unwrap();
+
+        subscriber::with_default(recorder, || {
+            app.dispatch(Ping).wait().ok().unwrap();
+        });
+
+        let spans = spans.lock().unwrap();
+        assert_eq!("dispatch", spans[0]);
+        assert_eq!("interceptor noop before", spans[1]);
+        assert_eq!(5, spans.len());
+        assert_eq!("interceptor noop after", spans[4]);
+    }
+
+    #[test]
+    fn test_around_shares_the_dispatch_span() {
+        let recorder = Recorder::default();
+        let spans = Arc::clone(&recorder.spans);
+        let app = EventDispatcher::new();
+        let wrap = around_fn(|context: Context<()>, next: Next<()>| next.run(context));
+        app.register_event::<Ping>(vec![Box::new(wrap), Box::new(Noop)]).unwrap();
+
+        subscriber::with_default(recorder, || {
+            app.dispatch(Ping).wait().ok().unwrap();
+        });
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(1, spans.iter().filter(|span| *span == "dispatch").count());
+        assert!(spans.contains(&"interceptor noop before".to_string()));
+    }
+}
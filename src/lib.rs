@@ -14,13 +14,28 @@
 // along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
 
 extern crate anymap;
-#[macro_use]
 extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate tokio_core;
+#[cfg(any(feature = "metrics", feature = "debug-server"))]
+extern crate tokio_io;
+#[cfg(feature = "debug-server")]
+extern crate tokio_uds;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "debug-server"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+#[cfg_attr(feature = "debug-server", macro_use)]
+extern crate serde_json;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 
+use std::any::{Any,type_name};
+use std::cell::RefCell;
 use std::mem;
 use std::sync::Arc;
 use std::rc::Rc;
@@ -29,7 +44,22 @@ use anymap::AnyMap;
 use futures::{future,Async,Future};
 
 mod app;
-pub use app::App;
+pub use app::{App,AppBuilder,Layer};
+
+mod around;
+pub use around::{Around,AroundInterceptor,Next,around};
+
+mod batch;
+pub use batch::{AnyEvent,DispatchAll};
+
+mod cancel;
+pub use cancel::CancelHandle;
+
+mod closure;
+pub use closure::{AfterFn,BeforeFn,IntoContextFuture,after_fn,around_fn,before_fn};
+
+mod combinators;
+pub use combinators::{Chain,Route,When,chain,route,when};
 
 mod coeffects;
 pub use coeffects::{Coeffect,NewCoeffect,InjectCoeffect};
@@ -37,20 +67,74 @@ pub use coeffects::{Coeffect,NewCoeffect,InjectCoeffect};
 mod db;
 pub use db::Db;
 
-mod effects;
-pub use effects::{Effect,HandleEffects};
+#[cfg(feature = "debug-server")]
+mod debug;
+#[cfg(feature = "debug-server")]
+pub use debug::{DebugInterceptor,Debugger};
+
+mod diff;
+pub use diff::{Change,Diff,DiffState,StateDiff};
+
+pub mod effects;
+pub use effects::{Compensated,Compensation,Declared,Effect,Effects,HandleEffects,Phase,compensate,declare};
+
+mod error;
+pub use error::{DispatchError,RegistrationError};
 
 mod events;
-pub use events::{Event,EventDispatcher,EventInterceptor,Dispatch,Dispatcher};
+pub use events::{Event,EventDispatcher,EventInterceptor,Dispatch,Dispatcher,InjectDispatcher,Registration};
+
+mod meta;
+pub use meta::{EventId,EventMeta};
+
+mod metrics;
+pub use metrics::{EventMetrics,Histogram,Metrics,MetricsInterceptor};
+
+mod lifecycle;
+pub use lifecycle::{AppStarted,AppStopping,Lifecycle,Run,Shutdown,Tracked};
+
+mod instrument;
+pub use instrument::Outcome;
+use instrument::{CallHook,CallSpan,DispatchSpan,Timing};
+
+mod observe;
+pub use observe::{LagPolicy,Observation,Observed,Subscription};
+
+mod panic;
+pub use panic::CatchPanic;
 
 mod queue;
 pub use queue::InterceptorQueue;
 
+mod source;
+pub use source::{ErrorPolicy,SourceHandle,StdinLines,stdin_lines};
+
+mod stores;
+pub use stores::{Access,StoreId};
+
+mod timeout;
+pub use timeout::Timeout;
+use timeout::Deadline;
+
+/// Where a dispatch keeps the `DispatchError` it failed with, for its
+/// observers, whatever its error type.
+type Failure = Rc<RefCell<Option<DispatchError>>>;
+
 pub struct Context<E> {
     pub coeffects: AnyMap,
     pub effects: Vec<Box<Effect>>,
     pub queue: InterceptorQueue<E>,
-    pub stack: Vec<Rc<Box<Interceptor<Error = E>>>>,
+    pub stack: InterceptorQueue<E>,
+    executed: Vec<Rc<Box<Interceptor<Error = E>>>>,
+    meta: Option<EventMeta>,
+    deadline: Option<Deadline>,
+    catch_panics: bool,
+    cancel: Option<CancelHandle>,
+    dry_run: Option<Vec<String>>,
+    failure: Failure,
+    handled: bool,
+    call_hook: Option<CallHook>,
+    span: Option<DispatchSpan>,
 }
 
 impl<E> Context<E> {
@@ -59,7 +143,17 @@ impl<E> Context<E> {
             coeffects: AnyMap::new(),
             effects: vec![],
             queue: interceptors.into_iter().collect(),
-            stack: vec![],
+            stack: InterceptorQueue::new(),
+            executed: vec![],
+            meta: None,
+            deadline: None,
+            catch_panics: false,
+            cancel: None,
+            dry_run: None,
+            failure: Failure::default(),
+            handled: false,
+            call_hook: None,
+            span: None,
         }
     }
 
@@ -67,6 +161,66 @@ impl<E> Context<E> {
         self.effects.push(Box::new(effect));
     }
 
+    /// The type name of the event being dispatched, if this context
+    /// was created by an `EventDispatcher`.
+    pub fn event_name(&self) -> Option<&'static str> {
+        self.meta.as_ref().map(|meta| meta.event)
+    }
+
+    /// Metadata about the dispatch, if this context was created by an
+    /// `EventDispatcher`.
+    pub fn meta(&self) -> Option<&EventMeta> {
+        self.meta.as_ref()
+    }
+
+    /// Whether the event's handler has been called. A dispatch can
+    /// finish without it if an interceptor clears the queue first.
+    pub fn handled(&self) -> bool {
+        self.handled
+    }
+
+    /// For a dry run, the descriptions of the effects `HandleEffects`
+    /// left unperformed, in the order it would have performed them.
+    /// `None` if this is not a dry run.
+    pub fn dry_run(&self) -> Option<&[String]> {
+        self.dry_run.as_ref().map(|described| &described[..])
+    }
+
+    /// Fail the dispatch with `error`. Return the result from `before`
+    /// or `after` as the error of their future. The `DispatchError`
+    /// itself is kept for the dispatch's observers, even when `E`
+    /// cannot carry it.
+    pub fn fail(&self, error: DispatchError) -> E
+    where E: From<DispatchError>,
+    {
+        *self.failure.borrow_mut() = Some(error.clone());
+        E::from(error)
+    }
+
+    /// Names of the interceptors that have been called so far, in
+    /// the order they were called. An interceptor appears twice once
+    /// both its `before` and `after` have run.
+    pub fn executed(&self) -> Vec<&str>
+    where E: 'static
+    {
+        self.executed.iter().map(|i| i.name()).collect()
+    }
+
+    /// Names of the interceptors still waiting in the queue.
+    pub fn pending(&self) -> Vec<&str>
+    where E: 'static
+    {
+        self.queue.iter().map(|i| i.name()).collect()
+    }
+
+    /// Names of the interceptors on the stack, which will have their
+    /// `after` called once the queue is exhausted.
+    pub fn stacked(&self) -> Vec<&str>
+    where E: 'static
+    {
+        self.stack.iter().map(|i| i.name()).collect()
+    }
+
     pub fn next(self) -> Box<Future<Item = Context<E>, Error = E>>
     where E: 'static
     {
@@ -74,9 +228,15 @@ impl<E> Context<E> {
     }
 }
 
-pub trait Interceptor {
+pub trait Interceptor: Any {
     type Error: 'static;
 
+    /// A human readable name used when describing an interceptor
+    /// chain. Defaults to the name of the implementing type.
+    fn name(&self) -> &str {
+        type_name::<Self>()
+    }
+
     fn before(&self, context: Context<Self::Error>) -> Box<Future<Item = Context<Self::Error>,
                                                                   Error = Self::Error>> {
         Box::new(future::ok(context))
@@ -86,11 +246,21 @@ pub trait Interceptor {
                                                                  Error = Self::Error>> {
         Box::new(future::ok(context))
     }
+
+    /// Called when the dispatch fails after this interceptor's
+    /// `before` completed but before its `after` ran. Interceptors are
+    /// notified in reverse order, like `after`. `EventMeta::current`
+    /// tells which dispatch it was.
+    fn error(&self, _error: &Self::Error) {}
 }
 
 impl<I: Interceptor + ?Sized> Interceptor for Arc<I> {
     type Error = I::Error;
 
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
     fn before(&self, context: Context<Self::Error>) -> Box<Future<Item = Context<Self::Error>,
                                                                   Error = Self::Error>> {
         (**self).before(context)
@@ -100,11 +270,19 @@ impl<I: Interceptor + ?Sized> Interceptor for Arc<I> {
                                                                  Error = Self::Error>> {
         (**self).after(context)
     }
+
+    fn error(&self, error: &Self::Error) {
+        (**self).error(error)
+    }
 }
 
 impl<I: Interceptor + ?Sized> Interceptor for Rc<I> {
     type Error = I::Error;
 
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
     fn before(&self, context: Context<Self::Error>) -> Box<Future<Item = Context<Self::Error>,
                                                                   Error = Self::Error>> {
         (**self).before(context)
@@ -114,6 +292,10 @@ impl<I: Interceptor + ?Sized> Interceptor for Rc<I> {
                                                                  Error = Self::Error>> {
         (**self).after(context)
     }
+
+    fn error(&self, error: &Self::Error) {
+        (**self).error(error)
+    }
 }
 
 pub trait NewInterceptor
@@ -162,6 +344,13 @@ impl Direction {
             Direction::Backwards => true,
         }
     }
+
+    fn phase(&self) -> &'static str {
+        match *self {
+            Direction::Forwards => "before",
+            Direction::Backwards => "after",
+        }
+    }
 }
 
 /// Dispatched represents the eventual completion of an Event
@@ -170,39 +359,205 @@ impl Direction {
 /// `before` method. On reaching the end of the chain, the
 /// interceptors are iterated in the reverse order, and the
 /// Context is threaded through their `after` methods.
+///
+/// If any step fails, times out or is cancelled, the interceptors
+/// that were entered but not yet left have their `error` called in
+/// reverse order before the error is returned.
 struct Dispatched<E> {
+    meta: EventMeta,
     direction: Direction,
     next_ctx: Box<Future<Item = Context<E>, Error = E>>,
+    entered: Vec<Rc<Box<Interceptor<Error = E>>>>,
+    deadline: Option<Deadline>,
+    cancel: Option<CancelHandle>,
+    catch_panics: bool,
+    failure: Failure,
+    span: DispatchSpan,
+    call: Option<CallSpan>,
+    call_hook: Option<CallHook>,
+    timing: Option<Timing>,
 }
 
 impl<E> Dispatched<E> {
-    pub fn new(next_ctx: Box<Future<Item = Context<E>, Error = E>>) -> Dispatched<E> {
+    pub fn new(meta: &EventMeta, next_ctx: Box<Future<Item = Context<E>, Error = E>>) -> Dispatched<E> {
+        Dispatched::with_span(meta, DispatchSpan::new(meta), next_ctx)
+    }
+
+    pub fn with_span(meta: &EventMeta, span: DispatchSpan, next_ctx: Box<Future<Item = Context<E>, Error = E>>)
+                     -> Dispatched<E> {
         Dispatched {
+            meta: meta.clone(),
             direction: Direction::Forwards,
             next_ctx,
+            entered: vec![],
+            deadline: None,
+            cancel: None,
+            catch_panics: false,
+            failure: Failure::default(),
+            span,
+            call: None,
+            call_hook: None,
+            timing: None,
         }
     }
+
+    pub fn with_cancel(mut self, cancel: &CancelHandle) -> Dispatched<E> {
+        self.cancel = Some(cancel.clone());
+        self
+    }
 }
 
-impl<E: 'static> Future for Dispatched<E> {
+impl<E: 'static> Dispatched<E> {
+    fn fail(&mut self, error: E) -> E {
+        if let Some(call) = self.call.take() {
+            call.finish(Outcome::Err);
+        }
+        if let Some(timing) = self.timing.take() {
+            timing.fail();
+        }
+        for interceptor in self.entered.drain(..).rev() {
+            interceptor.error(&error);
+        }
+        self.span.finish(Outcome::Err);
+        error
+    }
+
+    /// Fail with an error raised by the dispatch machinery itself.
+    fn fail_with(&mut self, error: DispatchError) -> E
+    where E: From<DispatchError>,
+    {
+        *self.failure.borrow_mut() = Some(error.clone());
+        self.fail(E::from(error))
+    }
+
+    /// Note which interceptors `ctx` has been taken into and not yet
+    /// out of, so that they are the ones told if the dispatch fails
+    /// from here on.
+    fn enter(&mut self, ctx: &Context<E>) {
+        self.entered = if self.direction.is_forwards() {
+            ctx.stack.iter().cloned().collect()
+        } else {
+            ctx.queue.iter().rev().cloned().collect()
+        };
+    }
+
+    fn cancelled(&self) -> Option<DispatchError> {
+        if self.cancel.as_ref().is_some_and(CancelHandle::is_cancelled) {
+            debug!("dispatch of {} cancelled", self.meta.event);
+            Some(DispatchError::Cancelled { event: self.meta.event })
+        } else {
+            None
+        }
+    }
+
+    fn timed_out(&mut self) -> Option<DispatchError> {
+        let event = self.meta.event;
+        let after = match self.deadline {
+            Some(ref mut deadline) => {
+                if !deadline.poll_expired() {
+                    return None;
+                }
+                deadline.after()
+            },
+            None => return None,
+        };
+        warn!("dispatch of {} timed out after {:?}", event, after);
+        Some(DispatchError::TimedOut { event, after })
+    }
+}
+
+impl<E> Future for Dispatched<E>
+where E: 'static + From<DispatchError>,
+{
     type Item = Context<E>;
     type Error = E;
 
     fn poll(&mut self) -> Result<Async<Context<E>>, E> {
+        let _dispatch = self.span.enter();
+        let _current = meta::enter(&self.meta);
         loop {
-            let mut ctx = try_ready!(self.next_ctx.poll());
+            let polled = {
+                let _call = self.call.as_ref().map(CallSpan::enter);
+                let next_ctx = &mut self.next_ctx;
+                panic::guard(self.catch_panics, self.meta.event, || next_ctx.poll())
+            };
+            let polled = match polled {
+                Ok(polled) => polled,
+                Err(error) => return Err(self.fail_with(error)),
+            };
+            let mut ctx = match polled {
+                Ok(Async::Ready(ctx)) => ctx,
+                Ok(Async::NotReady) => {
+                    if let Some(error) = self.timed_out() {
+                        return Err(self.fail_with(error));
+                    }
+                    return Ok(Async::NotReady);
+                },
+                Err(e) => return Err(self.fail(e)),
+            };
+            if let Some(call) = self.call.take() {
+                call.finish(Outcome::Ok);
+            }
+            if let Some(timing) = self.timing.take() {
+                timing.finish(ctx.effects.len());
+            }
+            self.enter(&ctx);
+            self.catch_panics = self.catch_panics || ctx.catch_panics;
+            if ctx.call_hook.is_some() {
+                self.call_hook = ctx.call_hook.clone();
+            }
+            if let Some(deadline) = ctx.deadline.take() {
+                self.deadline = Some(match self.deadline.take() {
+                    Some(existing) => existing.earliest(deadline),
+                    None => deadline,
+                });
+            }
+            if let Some(error) = self.cancelled() {
+                return Err(self.fail_with(error));
+            }
+            if let Some(error) = self.timed_out() {
+                return Err(self.fail_with(error));
+            }
             if let Some(next) = ctx.queue.pop_front() {
-                ctx.stack.push(Rc::clone(&next));
-                self.next_ctx = self.direction.call(next, ctx);
+                self.enter(&ctx);
+                if self.direction.is_forwards() {
+                    ctx.stack.push_back(Rc::clone(&next));
+                }
+                ctx.executed.push(Rc::clone(&next));
+                // Handed down so that the rest of the chain run by an
+                // `around` interceptor is stopped, protected and timed
+                // alike.
+                ctx.catch_panics = self.catch_panics;
+                ctx.cancel = self.cancel.clone();
+                ctx.failure = Rc::clone(&self.failure);
+                ctx.call_hook = self.call_hook.clone();
+                ctx.span = Some(self.span.nested());
+                let timing = self.call_hook.as_ref()
+                    .map(|hook| Timing::start(hook, next.name(), self.direction.phase(), ctx.effects.len()));
+                let call = self.span.call(next.name(), self.direction.phase());
+                let called = {
+                    let _call = call.enter();
+                    let direction = &self.direction;
+                    panic::guard(self.catch_panics, self.meta.event, move || direction.call(next, ctx))
+                };
+                self.call = Some(call);
+                self.timing = timing;
+                match called {
+                    Ok(next_ctx) => self.next_ctx = next_ctx,
+                    Err(error) => return Err(self.fail_with(error)),
+                }
                 continue;
             } else {
                 if self.direction.is_forwards() {
                     self.direction = Direction::Backwards;
-                    let stack = mem::replace(&mut ctx.stack, vec![]);
+                    let stack = mem::take(&mut ctx.stack);
                     ctx.queue = stack.into_iter().rev().collect();
                     self.next_ctx = Box::new(future::ok(ctx));
                     continue;
                 } else {
+                    self.span.finish(Outcome::Ok);
+                    ctx.deadline = self.deadline.take();
+                    ctx.span = None;
                     return Ok(Async::Ready(ctx));
                 }
             }
@@ -210,6 +565,32 @@ impl<E: 'static> Future for Dispatched<E> {
     }
 }
 
+/// Support for the code generated by `tokio-interceptor-macros`. Not
+/// part of the public API.
+#[doc(hidden)]
+pub mod __private {
+    use std::any::type_name;
+
+    use futures::{future,Future};
+
+    use super::{Context,DispatchError};
+
+    pub type BoxFuture<E> = Box<Future<Item = Context<E>, Error = E>>;
+
+    pub fn done<E: 'static>(context: Context<E>) -> BoxFuture<E> {
+        Box::new(future::ok(context))
+    }
+
+    pub fn missing_coeffect<Ev, C, E>(context: &Context<E>) -> BoxFuture<E>
+    where Ev: ?Sized,
+          C: ?Sized,
+          E: 'static + From<DispatchError>,
+    {
+        let error = DispatchError::MissingCoeffect { event: type_name::<Ev>(), coeffect: type_name::<C>() };
+        Box::new(future::err(context.fail(error)))
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -218,7 +599,7 @@ pub mod tests {
     use std::rc::Rc;
 
 
-    #[derive(Debug,PartialEq)]
+    #[derive(Clone,Debug,PartialEq)]
     pub struct State(pub u8);
 
     pub struct StateHolder(pub Rc<State>);
@@ -252,8 +633,8 @@ pub mod tests {
 
     #[test]
     fn test_dispatcher_calls_event_before() {
-        let mut app = EventDispatcher::new();
-        app.register_event::<BeforeEvent>(vec![]);
+        let app = EventDispatcher::new();
+        app.register_event::<BeforeEvent>(vec![]).unwrap();
         let called = Rc::new(RefCell::new(false));
         app.dispatch(BeforeEvent(Rc::clone(&called))).wait();
         assert_eq!(true, *called.borrow());
@@ -280,11 +661,11 @@ pub mod tests {
 
     #[test]
     fn test_dispatcher_calls_interceptor_before() {
-        let mut app = EventDispatcher::new();
+        let app = EventDispatcher::new();
 
         let called_first = Rc::new(RefCell::new(false));
         let before_inter = BeforeInter(Rc::clone(&called_first));
-        app.register_event::<BeforeEvent>(vec![Box::new(before_inter)]);
+        app.register_event::<BeforeEvent>(vec![Box::new(before_inter)]).unwrap();
 
         let called_second = Rc::new(RefCell::new(false));
         app.dispatch(BeforeEvent(Rc::clone(&called_second))).wait();
@@ -307,7 +688,7 @@ pub mod tests {
 
     #[test]
     fn test_dispatcher_calls_interceptor_after() {
-        let mut app = EventDispatcher::new();
+        let app = EventDispatcher::new();
 
         let called_first = Rc::new(RefCell::new(false));
         let before_inter = BeforeInter(Rc::clone(&called_first));
@@ -316,7 +697,7 @@ pub mod tests {
         let after_inter = AfterInter(Rc::clone(&called_third));
 
         app.register_event::<BeforeEvent>(vec![Box::new(before_inter),
-                                               Box::new(after_inter)]);
+                                               Box::new(after_inter)]).unwrap();
 
         let called_second = Rc::new(RefCell::new(false));
         app.dispatch(BeforeEvent(Rc::clone(&called_second))).wait();
@@ -325,4 +706,51 @@ pub mod tests {
         assert_eq!(true, *called_second.borrow());
         assert_eq!(true, *called_third.borrow());
     }
+
+    struct Named(&'static str, Rc<RefCell<Vec<String>>>);
+
+    impl Interceptor for Named {
+        type Error = ();
+
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn before(&self, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            let mut seen = self.1.borrow_mut();
+            seen.extend(context.executed().into_iter().map(String::from));
+            seen.push("|".to_string());
+            seen.extend(context.pending().into_iter().map(String::from));
+            Box::new(future::ok(context))
+        }
+    }
+
+    #[test]
+    fn test_describe_lists_registered_chain() {
+        let app = EventDispatcher::new();
+        let seen = Rc::new(RefCell::new(vec![]));
+        app.register_event::<IdentityEvent>(vec![Box::new(Named("first", Rc::clone(&seen))),
+                                                 Box::new(Named("second", Rc::clone(&seen)))]).unwrap();
+
+        assert_eq!(Some(vec!["first".to_string(),
+                             "second".to_string(),
+                             type_name::<IdentityEvent>().to_string()]),
+                   app.describe::<IdentityEvent>());
+        assert_eq!(None, app.describe::<BeforeEvent>());
+    }
+
+    #[test]
+    fn test_context_names_executed_and_pending() {
+        let app = EventDispatcher::new();
+        let seen = Rc::new(RefCell::new(vec![]));
+        app.register_event::<IdentityEvent>(vec![Box::new(Named("first", Rc::clone(&seen)))]).unwrap();
+
+        let context = app.dispatch(IdentityEvent).wait().ok().unwrap();
+
+        let event = type_name::<IdentityEvent>();
+        assert_eq!(vec!["first", "|", event], *seen.borrow());
+        assert_eq!(vec!["first", event, event, "first"], context.executed());
+        assert!(context.pending().is_empty());
+        assert_eq!(0, context.stacked().len());
+    }
 }
@@ -0,0 +1,313 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use futures::{future,Async,Future,Poll};
+use futures::task::{self,Task};
+use tokio_core::reactor::Handle;
+
+use super::{Context,DispatchError,Effect,Event,EventDispatcher};
+
+struct LifecycleState {
+    in_flight: usize,
+    exit_code: Option<i32>,
+    tasks: Vec<Task>,
+}
+
+/// Shared run state of an `App`: whether shutdown has been requested
+/// and how many tracked dispatches are still in flight.
+#[derive(Clone)]
+pub struct Lifecycle(Rc<RefCell<LifecycleState>>);
+
+impl Lifecycle {
+    pub fn new() -> Lifecycle {
+        Lifecycle(Rc::new(RefCell::new(LifecycleState {
+            in_flight: 0,
+            exit_code: None,
+            tasks: vec![],
+        })))
+    }
+
+    /// An effect that asks the app to shut down with `exit_code`.
+    pub fn shutdown(&self, exit_code: i32) -> Shutdown {
+        Shutdown { lifecycle: self.clone(), exit_code }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.0.borrow().exit_code.is_some()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.0.borrow().in_flight
+    }
+
+    /// Count `future` as in flight until it completes, so that a
+    /// shutting down app waits for it.
+    pub fn track<F: Future>(&self, future: F) -> Tracked<F> {
+        self.0.borrow_mut().in_flight += 1;
+        Tracked { lifecycle: self.clone(), future, done: false }
+    }
+
+    fn request_shutdown(&self, exit_code: i32) {
+        let mut state = self.0.borrow_mut();
+        if state.exit_code.is_none() {
+            state.exit_code = Some(exit_code);
+        }
+        for task in state.tasks.drain(..) {
+            task.notify();
+        }
+    }
+
+    fn finished(&self) {
+        let mut state = self.0.borrow_mut();
+        state.in_flight -= 1;
+        for task in state.tasks.drain(..) {
+            task.notify();
+        }
+    }
+
+    /// Wake the current task the next time shutdown is requested or a
+    /// tracked future completes.
+    pub fn park(&self) {
+        let mut state = self.0.borrow_mut();
+        if !state.tasks.iter().any(Task::will_notify_current) {
+            state.tasks.push(task::current());
+        }
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.0.borrow().exit_code
+    }
+}
+
+impl Default for Lifecycle {
+    fn default() -> Lifecycle {
+        Lifecycle::new()
+    }
+}
+
+/// Effect requesting a graceful shutdown; see `App::run`.
+pub struct Shutdown {
+    lifecycle: Lifecycle,
+    exit_code: i32,
+}
+
+impl Effect for Shutdown {
+    fn action(self: Box<Self>) {
+        self.lifecycle.request_shutdown(self.exit_code);
+    }
+
+    fn describe(&self) -> String {
+        format!("shut down with exit code {}", self.exit_code)
+    }
+}
+
+/// A future counted as in flight by a `Lifecycle`.
+pub struct Tracked<F> {
+    lifecycle: Lifecycle,
+    future: F,
+    done: bool,
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let polled = self.future.poll();
+        match polled {
+            Ok(Async::NotReady) => {},
+            _ => {
+                self.done = true;
+                self.lifecycle.finished();
+            },
+        }
+        polled
+    }
+}
+
+impl<F> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.lifecycle.finished();
+        }
+    }
+}
+
+/// Dispatched when an `App` starts running.
+pub struct AppStarted;
+
+impl<E: 'static> Event<E> for AppStarted {
+    fn handle(self: Box<Self>, context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        Box::new(future::ok(context))
+    }
+}
+
+/// Dispatched once an `App` has been asked to shut down, before it
+/// drains. The event is available to interceptors as a coeffect.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct AppStopping {
+    pub exit_code: i32,
+}
+
+impl<E: 'static> Event<E> for AppStopping {
+    fn handle(self: Box<Self>, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        context.coeffects.insert(*self);
+        Box::new(future::ok(context))
+    }
+}
+
+enum Phase {
+    Starting, Running, Stopping(i32),
+}
+
+/// The future returned by `App::run`.
+pub struct Run<E> {
+    phase: Phase,
+    handle: Handle,
+    lifecycle: Lifecycle,
+    dispatcher: EventDispatcher<E>,
+}
+
+impl<E> Run<E>
+where E: 'static + From<DispatchError>,
+{
+    pub fn new(handle: &Handle, lifecycle: &Lifecycle, dispatcher: &EventDispatcher<E>) -> Run<E> {
+        Run {
+            phase: Phase::Starting,
+            handle: handle.clone(),
+            lifecycle: lifecycle.clone(),
+            dispatcher: dispatcher.clone(),
+        }
+    }
+
+    fn spawn<Ev: 'static + Event<E>>(&self, event: Ev) {
+        if !self.dispatcher.is_registered::<Ev>() {
+            return;
+        }
+        let dispatched = self.dispatcher.dispatch(event);
+        self.handle.spawn(self.lifecycle.track(dispatched).map(|_| ()).map_err(|_| ()));
+    }
+}
+
+impl<E> Future for Run<E>
+where E: 'static + From<DispatchError>,
+{
+    type Item = i32;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<i32, ()> {
+        loop {
+            match self.phase {
+                Phase::Starting => {
+                    self.spawn(AppStarted);
+                    self.phase = Phase::Running;
+                },
+                Phase::Running => {
+                    match self.lifecycle.exit_code() {
+                        Some(exit_code) => {
+                            info!("shutting down with exit code {}", exit_code);
+                            self.spawn(AppStopping { exit_code });
+                            self.phase = Phase::Stopping(exit_code);
+                        },
+                        None => {
+                            self.lifecycle.park();
+                            return Ok(Async::NotReady);
+                        },
+                    }
+                },
+                Phase::Stopping(exit_code) => {
+                    if self.lifecycle.in_flight() == 0 {
+                        return Ok(Async::Ready(exit_code));
+                    }
+                    self.lifecycle.park();
+                    return Ok(Async::NotReady);
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    use tokio_core::reactor::Core;
+
+    use {App,Dispatcher,Interceptor};
+
+    struct Stop(i32);
+
+    impl Event<()> for Stop {
+        fn handle(self: Box<Self>, mut context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            {
+                let dispatcher = context.coeffects.get::<Dispatcher<()>>().unwrap();
+                context.effects.push(dispatcher.shutdown(self.0));
+            }
+            Box::new(future::ok(context))
+        }
+    }
+
+    struct StopOnStart;
+
+    impl Interceptor for StopOnStart {
+        type Error = ();
+
+        fn before(&self, mut context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            {
+                let dispatcher = context.coeffects.get::<Dispatcher<()>>().unwrap();
+                context.effects.push(dispatcher.dispatch(Stop(3)));
+            }
+            Box::new(future::ok(context))
+        }
+    }
+
+    struct RecordStopping(Rc<Cell<Option<i32>>>);
+
+    impl Interceptor for RecordStopping {
+        type Error = ();
+
+        fn after(&self, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            self.0.set(context.coeffects.get::<AppStopping>().map(|e| e.exit_code));
+            Box::new(future::ok(context))
+        }
+    }
+
+    #[test]
+    fn test_run_resolves_with_shutdown_exit_code() {
+        let mut core = Core::new().unwrap();
+        let app: App<()> = App::new(core.handle());
+        let stopping = Rc::new(Cell::new(None));
+        app.register_event::<Stop>().unwrap();
+        app.register_event_with::<AppStarted>(vec![Box::new(StopOnStart)]).unwrap();
+        app.register_event_with::<AppStopping>(vec![Box::new(RecordStopping(Rc::clone(&stopping)))]).unwrap();
+
+        assert_eq!(Ok(3), core.run(app.run()));
+        assert_eq!(Some(3), stopping.get());
+    }
+
+    #[test]
+    fn test_tracked_future_counts_in_flight() {
+        let lifecycle = Lifecycle::new();
+        let tracked = lifecycle.track(future::ok::<(), ()>(()));
+        assert_eq!(1, lifecycle.in_flight());
+        tracked.wait().unwrap();
+        assert_eq!(0, lifecycle.in_flight());
+    }
+}
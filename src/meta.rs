@@ -0,0 +1,146 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::time::SystemTime;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The dispatches being polled on this thread, innermost last.
+    static CURRENT: RefCell<Vec<EventMeta>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Identifies one dispatch. Ids are unique within the process.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub struct EventId(u64);
+
+impl EventId {
+    fn next() -> EventId {
+        EventId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Describes a dispatch and where it came from. Every context created
+/// by an `EventDispatcher` carries one, available from
+/// `Context::meta`.
+///
+/// A dispatch started from a `Dispatcher` coeffect is caused by the
+/// dispatch that coeffect was injected into: its `parent` is that
+/// dispatch's id and its `root` is shared with it, so a whole cascade
+/// of events can be traced back to the one that started it.
+#[derive(Clone,Debug,PartialEq)]
+pub struct EventMeta {
+    pub id: EventId,
+    /// When the dispatch was started.
+    pub time: SystemTime,
+    pub parent: Option<EventId>,
+    /// The id of the first dispatch in the chain of causes; the
+    /// dispatch's own id if it has no parent.
+    pub root: EventId,
+    /// The type name of the event.
+    pub event: &'static str,
+}
+
+impl EventMeta {
+    /// Metadata for a dispatch of `event` with no cause.
+    pub fn root(event: &'static str) -> EventMeta {
+        let id = EventId::next();
+        EventMeta { id, time: SystemTime::now(), parent: None, root: id, event }
+    }
+
+    /// Metadata for a dispatch of `event` caused by the dispatch
+    /// described by `self`.
+    pub fn child(&self, event: &'static str) -> EventMeta {
+        EventMeta {
+            id: EventId::next(),
+            time: SystemTime::now(),
+            parent: Some(self.id),
+            root: self.root,
+            event,
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.parent.is_none()
+    }
+
+    /// The dispatch being polled on this thread, if any. An
+    /// interceptor's `error` is not given the context, and can use
+    /// this to tell which dispatch failed.
+    pub fn current() -> Option<EventMeta> {
+        CURRENT.with(|current| current.borrow().last().cloned())
+    }
+}
+
+/// Makes a dispatch current until dropped; see `EventMeta::current`.
+pub struct Current(());
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().pop());
+    }
+}
+
+pub fn enter(meta: &EventMeta) -> Current {
+    CURRENT.with(|current| current.borrow_mut().push(meta.clone()));
+    Current(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_shares_root() {
+        let root = EventMeta::root("Input");
+        let child = root.child("ShowPrompt");
+        let grandchild = child.child("ShowMenu");
+
+        assert!(root.is_root());
+        assert_eq!(Some(root.id), child.parent);
+        assert_eq!(Some(child.id), grandchild.parent);
+        assert_eq!(root.id, grandchild.root);
+        assert!(root.id < child.id && child.id < grandchild.id);
+    }
+
+    #[test]
+    fn test_current_is_innermost_entered() {
+        let root = EventMeta::root("Input");
+        let child = root.child("ShowPrompt");
+        assert_eq!(None, EventMeta::current());
+        {
+            let _root = enter(&root);
+            {
+                let _child = enter(&child);
+                assert_eq!(Some(child.id), EventMeta::current().map(|meta| meta.id));
+            }
+            assert_eq!(Some(root.id), EventMeta::current().map(|meta| meta.id));
+        }
+        assert_eq!(None, EventMeta::current());
+    }
+}
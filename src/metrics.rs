@@ -0,0 +1,456 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+#[cfg(feature = "metrics")]
+use std::io;
+use std::marker::PhantomData;
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration,Instant};
+
+use futures::{future,Future};
+#[cfg(feature = "metrics")]
+use futures::Stream;
+#[cfg(feature = "metrics")]
+use tokio_core::net::TcpListener;
+#[cfg(feature = "metrics")]
+use tokio_core::reactor::Handle;
+#[cfg(feature = "metrics")]
+use tokio_io::io::{read,write_all};
+
+use super::{Context,EventMeta,Interceptor};
+use instrument::CallHook;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 14] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05,
+                            0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A counter's name, help text and value.
+type Counter = (&'static str, &'static str, fn(&EventMetrics) -> u64);
+
+/// A latency distribution.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram { counts: vec![0; BUCKETS.len()], count: 0, sum: Duration::from_secs(0) }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += elapsed;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// How many observations took at most each bucket's bound, in
+    /// seconds. Observations beyond the last bound are only in
+    /// `count`.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        BUCKETS.iter().zip(&self.counts).map(|(&bound, &count)| {
+            total += count;
+            (bound, total)
+        }).collect()
+    }
+}
+
+/// What happened to the dispatches of one event type.
+///
+/// Every dispatch counted in `dispatched` ends up in exactly one of
+/// `succeeded`, `failed` or `halted` once it completes. A dispatch is
+/// halted if it completed without its event handler being called,
+/// because an interceptor cleared the rest of the queue.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct EventMetrics {
+    pub dispatched: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub halted: u64,
+    /// Effects taken out of the context to be performed.
+    pub effects: u64,
+    /// Latency of the whole chain, for dispatches that completed.
+    pub latency: Histogram,
+    /// Latency of each interceptor's `before` and `after`, keyed by
+    /// its name and the phase.
+    pub interceptors: BTreeMap<(String, &'static str), Histogram>,
+}
+
+/// Dispatch metrics, keyed by event type. A `Metrics` is a cheap
+/// handle; clones share the same counts.
+///
+/// Metrics are collected by the interceptor made by `interceptor`,
+/// which has to come first in the chain, and can be read back with
+/// `snapshot` or rendered in the Prometheus text format with
+/// `prometheus`. With the `metrics` feature they can also be served
+/// over HTTP with `serve`.
+#[derive(Clone,Default)]
+pub struct Metrics(Rc<RefCell<BTreeMap<&'static str, EventMetrics>>>);
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn interceptor<E>(&self) -> MetricsInterceptor<E> {
+        MetricsInterceptor { metrics: self.clone(), phantom: PhantomData }
+    }
+
+    /// The metrics collected so far.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, EventMetrics> {
+        self.0.borrow().clone()
+    }
+
+    fn update<F: FnOnce(&mut EventMetrics)>(&self, event: &'static str, f: F) {
+        f(self.0.borrow_mut().entry(event).or_default())
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let metrics = self.0.borrow();
+        let mut out = String::new();
+        let counters: [Counter; 5] = [
+            ("dispatches", "Dispatches started.", |m| m.dispatched),
+            ("succeeded", "Dispatches that completed.", |m| m.succeeded),
+            ("failed", "Dispatches that failed.", |m| m.failed),
+            ("halted", "Dispatches that completed without running their handler.", |m| m.halted),
+            ("effects", "Effects performed.", |m| m.effects),
+        ];
+        for &(name, help, value) in counters.iter() {
+            let _ = writeln!(out, "# HELP tokio_interceptor_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE tokio_interceptor_{}_total counter", name);
+            for (event, m) in metrics.iter() {
+                let _ = writeln!(out, "tokio_interceptor_{}_total{{event=\"{}\"}} {}", name, escape(event), value(m));
+            }
+        }
+
+        let _ = writeln!(out, "# HELP tokio_interceptor_dispatch_duration_seconds Latency of completed dispatches.");
+        let _ = writeln!(out, "# TYPE tokio_interceptor_dispatch_duration_seconds histogram");
+        for (event, m) in metrics.iter() {
+            let labels = format!("event=\"{}\"", escape(event));
+            write_histogram(&mut out, "tokio_interceptor_dispatch_duration_seconds", &labels, &m.latency);
+        }
+
+        let _ = writeln!(out, "# HELP tokio_interceptor_interceptor_duration_seconds Latency of interceptor calls.");
+        let _ = writeln!(out, "# TYPE tokio_interceptor_interceptor_duration_seconds histogram");
+        for (event, m) in metrics.iter() {
+            for (&(ref interceptor, phase), histogram) in m.interceptors.iter() {
+                let labels = format!("event=\"{}\",interceptor=\"{}\",phase=\"{}\"",
+                                     escape(event), escape(interceptor), phase);
+                write_histogram(&mut out, "tokio_interceptor_interceptor_duration_seconds", &labels, histogram);
+            }
+        }
+        out
+    }
+
+    /// Serve the metrics over HTTP at `addr`, returning the address
+    /// bound. Any `GET` request is answered with `prometheus`.
+    #[cfg(feature = "metrics")]
+    pub fn serve(&self, addr: &SocketAddr, handle: &Handle) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr, handle)?;
+        let local = listener.local_addr()?;
+        let metrics = self.clone();
+        let spawner = handle.clone();
+        handle.spawn(listener.incoming()
+                     .for_each(move |(stream, _)| {
+                         let metrics = metrics.clone();
+                         spawner.spawn(read(stream, vec![0; 1024])
+                                       .and_then(move |(stream, request, n)| {
+                                           write_all(stream, metrics.respond(&request[..n]))
+                                       })
+                                       .map(|_| ())
+                                       .map_err(|e| debug!("metrics request failed: {}", e)));
+                         Ok(())
+                     })
+                     .map_err(|e| error!("metrics server stopped: {}", e)));
+        Ok(local)
+    }
+
+    #[cfg(feature = "metrics")]
+    fn respond(&self, request: &[u8]) -> String {
+        if request.starts_with(b"GET ") {
+            let body = self.prometheus();
+            format!("HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(), body)
+        } else {
+            "HTTP/1.0 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n".to_string()
+        }
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    for (bound, count) in histogram.buckets() {
+        let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+    }
+    let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, histogram.count);
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum.as_secs_f64());
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+}
+
+/// When the dispatch reached the `MetricsInterceptor`.
+struct Started(Instant);
+
+/// Collects metrics for the dispatches it is part of; see
+/// `Metrics::interceptor`.
+///
+/// Its `before` leaves a `CallHook` in the context, timing every call
+/// to an interceptor made after it, including those queued later,
+/// e.g. by `route`. Effects are not counted in a dry run, which only
+/// describes them.
+pub struct MetricsInterceptor<E> {
+    metrics: Metrics,
+    phantom: PhantomData<E>,
+}
+
+impl<E: 'static> Interceptor for MetricsInterceptor<E> {
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        let event = context.event_name().unwrap_or("unknown");
+        self.metrics.update(event, |m| m.dispatched += 1);
+        context.coeffects.insert(Started(Instant::now()));
+        let metrics = self.metrics.clone();
+        let dry_run = context.dry_run().is_some();
+        let hook: CallHook = Rc::new(move |interceptor, phase, elapsed, effects| {
+            metrics.update(event, |m| {
+                if !dry_run {
+                    m.effects += effects as u64;
+                }
+                m.interceptors.entry((interceptor.to_string(), phase)).or_default().observe(elapsed);
+            });
+        });
+        context.call_hook = Some(hook);
+        Box::new(future::ok(context))
+    }
+
+    fn after(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        let event = context.event_name().unwrap_or("unknown");
+        let halted = !context.handled();
+        let started = context.coeffects.remove::<Started>();
+        self.metrics.update(event, |m| {
+            if halted {
+                m.halted += 1;
+            } else {
+                m.succeeded += 1;
+            }
+            if let Some(Started(started)) = started {
+                m.latency.observe(started.elapsed());
+            }
+        });
+        Box::new(future::ok(context))
+    }
+
+    fn error(&self, _error: &E) {
+        if let Some(meta) = EventMeta::current() {
+            self.metrics.update(meta.event, |m| m.failed += 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    #[cfg(feature = "metrics")]
+    use std::io::{Read,Write};
+    #[cfg(feature = "metrics")]
+    use std::net::TcpStream;
+    #[cfg(feature = "metrics")]
+    use std::sync::mpsc;
+    #[cfg(feature = "metrics")]
+    use std::thread;
+
+    use tokio_core::reactor::Core;
+
+    use futures::Async;
+    use futures::sync::oneshot;
+
+    use {App,Db,Event,EventDispatcher,EventInterceptor,InterceptorQueue,before_fn};
+
+    #[derive(Clone,Default)]
+    struct Count(u32);
+
+    struct Increment;
+    struct Fail;
+    struct Halt;
+    struct Probe;
+    struct Later(oneshot::Receiver<()>);
+
+    impl Event<()> for Halt {
+        fn handle(self: Box<Self>, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            Box::new(future::ok(context))
+        }
+    }
+
+    impl Event<()> for Probe {
+        fn handle(self: Box<Self>, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            Box::new(future::ok(context))
+        }
+    }
+
+    fn app(core: &Core, metrics: &Metrics) -> App<Count> {
+        let app: App<Count> = App::builder(core.handle()).metrics(metrics).build();
+        app.register_fn(|Increment, mut context: Context<()>| {
+            let db = context.coeffects.remove::<Db<Count>>().unwrap();
+            context.push_effect(db.mutate(|count: &mut Count| count.0 += 1));
+            context
+        }).unwrap();
+        app.register_fn(|Fail, _context: Context<()>| future::err::<Context<()>, ()>(())).unwrap();
+        app.register_event_with::<Halt>(vec![Box::new(before_fn(|mut context: Context<()>| {
+            context.queue = InterceptorQueue::new();
+            context
+        }))]).unwrap();
+        app
+    }
+
+    fn named<'a>(snapshot: &'a BTreeMap<&'static str, EventMetrics>, suffix: &str) -> &'a EventMetrics {
+        snapshot.iter().find(|&(event, _)| event.ends_with(suffix)).unwrap().1
+    }
+
+    #[test]
+    fn test_counts_outcomes_and_effects() {
+        let mut core = Core::new().unwrap();
+        let metrics = Metrics::new();
+        let app = app(&core, &metrics);
+
+        core.run(app.dispatch(Increment)).ok().unwrap();
+        core.run(app.dispatch(Increment)).ok().unwrap();
+        assert!(core.run(app.dispatch(Fail)).is_err());
+        core.run(app.dispatch(Halt)).ok().unwrap();
+
+        let snapshot = app.metrics().unwrap();
+        let increment = named(&snapshot, "Increment");
+        assert_eq!((2, 2, 0, 0, 2), (increment.dispatched, increment.succeeded, increment.failed,
+                                     increment.halted, increment.effects));
+        assert_eq!(2, increment.latency.count());
+        assert_eq!(2, increment.interceptors[&("tokio_interceptor::effects::HandleEffects<()>".to_string(), "after")].count());
+        let fail = named(&snapshot, "Fail");
+        assert_eq!((1, 0, 1, 0), (fail.dispatched, fail.succeeded, fail.failed, fail.halted));
+        let halt = named(&snapshot, "Halt");
+        assert_eq!((1, 0, 0, 1), (halt.dispatched, halt.succeeded, halt.failed, halt.halted));
+    }
+
+    #[test]
+    fn test_queue_holds_the_interceptors_themselves() {
+        let mut core = Core::new().unwrap();
+        let metrics = Metrics::new();
+        let app = app(&core, &metrics);
+        let found = Rc::new(Cell::new(false));
+        let seen = Rc::clone(&found);
+        app.register_event_with::<Probe>(vec![Box::new(before_fn(move |context: Context<()>| {
+            seen.set(context.queue.contains::<EventInterceptor<Probe, ()>>());
+            context
+        }))]).unwrap();
+        core.run(app.dispatch(Probe)).ok().unwrap();
+
+        assert!(found.get());
+        let snapshot = app.metrics().unwrap();
+        let probe = named(&snapshot, "Probe");
+        assert_eq!((1, 1, 0), (probe.dispatched, probe.succeeded, probe.halted));
+        assert_eq!(1, probe.interceptors[&("tokio_interceptor::metrics::tests::Probe".to_string(), "before")].count());
+    }
+
+    #[test]
+    fn test_dry_run_counts_no_effects() {
+        let mut core = Core::new().unwrap();
+        let metrics = Metrics::new();
+        let app = app(&core, &metrics);
+
+        core.run(app.dispatch_dry_run(Increment)).ok().unwrap();
+
+        let snapshot = app.metrics().unwrap();
+        let increment = named(&snapshot, "Increment");
+        assert_eq!((1, 1, 0), (increment.dispatched, increment.succeeded, increment.effects));
+    }
+
+    #[test]
+    fn test_failure_counted_for_its_own_event_with_a_shared_interceptor() {
+        let mut core = Core::new().unwrap();
+        let metrics = Metrics::new();
+        let shared = Rc::new(metrics.interceptor::<()>());
+        let dispatcher: EventDispatcher<()> = EventDispatcher::new();
+        dispatcher.register_fn(vec![Box::new(Rc::clone(&shared))],
+                               |Later(rx), _context: Context<()>| rx.then(|_| Err::<Context<()>, ()>(())))
+            .unwrap();
+        dispatcher.register_fn(vec![Box::new(Rc::clone(&shared))], |Increment, context: Context<()>| context)
+            .unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+
+        let mut later = dispatcher.dispatch(Later(rx));
+        assert!(core.run(future::poll_fn(|| Ok::<_, ()>(Async::Ready(later.poll().is_ok())))).unwrap());
+        core.run(dispatcher.dispatch(Increment)).ok().unwrap();
+        drop(tx);
+        assert!(core.run(later).is_err());
+
+        let snapshot = metrics.snapshot();
+        assert_eq!((1, 0), (named(&snapshot, "Increment").succeeded, named(&snapshot, "Increment").failed));
+        assert_eq!((1, 1), (named(&snapshot, "Later").dispatched, named(&snapshot, "Later").failed));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_serves_prometheus_text() {
+        let mut core = Core::new().unwrap();
+        let metrics = Metrics::new();
+        let app = app(&core, &metrics);
+        core.run(app.dispatch(Increment)).ok().unwrap();
+
+        let addr = app.serve_metrics(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            tx.send(response).unwrap();
+        });
+        let response = loop {
+            core.turn(Some(Duration::from_millis(10)));
+            if let Ok(response) = rx.try_recv() {
+                break response;
+            }
+        };
+
+        assert!(response.starts_with("HTTP/1.0 200 OK"));
+        assert!(response.contains("tokio_interceptor_dispatches_total{event=\"tokio_interceptor::metrics::tests::Increment\"} 1"));
+        assert!(response.contains("tokio_interceptor_dispatch_duration_seconds_count{event=\"tokio_interceptor::metrics::tests::Increment\"} 1"));
+    }
+}
@@ -0,0 +1,249 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::{Rc,Weak};
+
+use futures::{Async,Future,Poll,Stream};
+use futures::task::{self,Task};
+
+use super::{DispatchError,EventMeta,Failure,Outcome};
+
+/// What to do when an observation arrives for a subscription whose
+/// buffer is full.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum LagPolicy {
+    /// Make room by discarding the oldest buffered observation.
+    DropOldest,
+    /// Discard the new observation.
+    DropNewest,
+    /// End the subscription: its stream finishes once the buffer has
+    /// been drained.
+    Disconnect,
+}
+
+/// What a subscription sees of one completed dispatch.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Observation {
+    pub meta: EventMeta,
+    pub outcome: Outcome,
+    /// For a failed dispatch, the `DispatchError` it failed with, if
+    /// it failed with one. Kept even when the dispatcher's error type
+    /// is `()`.
+    pub error: Option<DispatchError>,
+}
+
+struct Channel {
+    buffer: VecDeque<Observation>,
+    capacity: usize,
+    policy: LagPolicy,
+    lagged: u64,
+    closed: bool,
+    task: Option<Task>,
+}
+
+impl Channel {
+    fn push(&mut self, item: Observation) {
+        if self.closed {
+            return;
+        }
+        if self.buffer.len() >= self.capacity {
+            self.lagged += 1;
+            match self.policy {
+                LagPolicy::DropOldest => {
+                    self.buffer.pop_front();
+                    self.buffer.push_back(item);
+                },
+                LagPolicy::DropNewest => {},
+                LagPolicy::Disconnect => self.closed = true,
+            }
+        } else {
+            self.buffer.push_back(item);
+        }
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+struct Subscriber {
+    event: Option<TypeId>,
+    channel: Weak<RefCell<Channel>>,
+}
+
+/// The subscriptions of an `EventDispatcher`, shared by its clones.
+#[derive(Clone,Default)]
+pub struct Observers(Rc<RefCell<Vec<Subscriber>>>);
+
+impl Observers {
+    pub fn new() -> Observers {
+        Observers::default()
+    }
+
+    pub fn subscribe(&self, event: Option<TypeId>, capacity: usize, policy: LagPolicy) -> Subscription {
+        let channel = Rc::new(RefCell::new(Channel {
+            buffer: VecDeque::new(),
+            capacity: capacity.max(1),
+            policy,
+            lagged: 0,
+            closed: false,
+            task: None,
+        }));
+        self.0.borrow_mut().push(Subscriber { event, channel: Rc::downgrade(&channel) });
+        Subscription(channel)
+    }
+
+    fn notify(&self, event: TypeId, observation: Observation) {
+        let channels: Vec<Rc<RefCell<Channel>>> = {
+            let mut subscribers = self.0.borrow_mut();
+            subscribers.retain(|s| match s.channel.upgrade() {
+                Some(channel) => !channel.borrow().closed,
+                None => false,
+            });
+            subscribers.iter()
+                .filter(|s| s.event.is_none() || s.event == Some(event))
+                .filter_map(|s| s.channel.upgrade())
+                .collect()
+        };
+        for channel in channels {
+            channel.borrow_mut().push(observation.clone());
+        }
+    }
+
+    /// Report the outcome of `dispatched`, a dispatch of the event
+    /// described by `meta`, to the subscribers once it completes,
+    /// along with the error left in `failure` if it failed.
+    pub fn observe<F: Future>(&self, event: TypeId, meta: EventMeta, failure: Failure, dispatched: F) -> Observed<F> {
+        Observed { observers: self.clone(), event, meta, failure, dispatched }
+    }
+}
+
+/// A `Stream` of an `Observation` of each completed dispatch of the
+/// events it was subscribed to. Observations arrive once the
+/// whole chain, including its effects, has run.
+///
+/// The stream never fails, and only ends if its `LagPolicy` is
+/// `Disconnect` and it fell behind.
+pub struct Subscription(Rc<RefCell<Channel>>);
+
+impl Subscription {
+    /// How many observations were dropped because the buffer was full.
+    pub fn lagged(&self) -> u64 {
+        self.0.borrow().lagged
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Observation;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Observation>, ()> {
+        let mut channel = self.0.borrow_mut();
+        match channel.buffer.pop_front() {
+            Some(item) => Ok(Async::Ready(Some(item))),
+            None if channel.closed => Ok(Async::Ready(None)),
+            None => {
+                channel.task = Some(task::current());
+                Ok(Async::NotReady)
+            },
+        }
+    }
+}
+
+/// A dispatch that notifies the subscribers when it completes.
+pub struct Observed<F> {
+    observers: Observers,
+    event: TypeId,
+    meta: EventMeta,
+    failure: Failure,
+    dispatched: F,
+}
+
+impl<F: Future> Future for Observed<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let result = self.dispatched.poll();
+        let (outcome, error) = match result {
+            Ok(Async::NotReady) => return result,
+            Ok(Async::Ready(_)) => (Outcome::Ok, None),
+            Err(_) => (Outcome::Err, self.failure.borrow_mut().take()),
+        };
+        self.observers.notify(self.event, Observation { meta: self.meta.clone(), outcome, error });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+
+    struct Ping;
+    struct Pong;
+
+    fn observe(observers: &Observers, event: TypeId, failed: bool) {
+        let meta = EventMeta::root("Ping");
+        let dispatched = if failed { future::err::<(), ()>(()) } else { future::ok(()) };
+        let _ = observers.observe(event, meta, Failure::default(), dispatched).wait();
+    }
+
+    /// The buffered outcomes, and whether the stream has ended.
+    fn drain(subscription: &mut Subscription) -> (Vec<Outcome>, bool) {
+        future::poll_fn(|| -> Poll<(Vec<Outcome>, bool), ()> {
+            let mut outcomes = vec![];
+            loop {
+                match subscription.poll()? {
+                    Async::Ready(Some(observation)) => outcomes.push(observation.outcome),
+                    Async::Ready(None) => return Ok(Async::Ready((outcomes, true))),
+                    Async::NotReady => return Ok(Async::Ready((outcomes, false))),
+                }
+            }
+        }).wait().unwrap()
+    }
+
+    #[test]
+    fn test_subscribe_filters_by_event() {
+        let observers = Observers::new();
+        let mut pings = observers.subscribe(Some(TypeId::of::<Ping>()), 8, LagPolicy::DropOldest);
+        let mut all = observers.subscribe(None, 8, LagPolicy::DropOldest);
+
+        observe(&observers, TypeId::of::<Ping>(), false);
+        observe(&observers, TypeId::of::<Pong>(), true);
+
+        assert_eq!((vec![Outcome::Ok], false), drain(&mut pings));
+        assert_eq!((vec![Outcome::Ok, Outcome::Err], false), drain(&mut all));
+    }
+
+    #[test]
+    fn test_lag_policies() {
+        let observers = Observers::new();
+        let mut oldest = observers.subscribe(None, 1, LagPolicy::DropOldest);
+        let mut newest = observers.subscribe(None, 1, LagPolicy::DropNewest);
+        let mut disconnect = observers.subscribe(None, 1, LagPolicy::Disconnect);
+
+        observe(&observers, TypeId::of::<Ping>(), false);
+        observe(&observers, TypeId::of::<Ping>(), true);
+
+        assert_eq!((vec![Outcome::Err], false), drain(&mut oldest));
+        assert_eq!((vec![Outcome::Ok], false), drain(&mut newest));
+        assert_eq!(1, newest.lagged());
+        assert_eq!((vec![Outcome::Ok], true), drain(&mut disconnect));
+    }
+}
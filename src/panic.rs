@@ -0,0 +1,119 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::any::Any;
+use std::marker::PhantomData;
+use std::panic::{self,AssertUnwindSafe};
+
+use futures::{future,Future};
+
+use super::{Context,DispatchError,Interceptor};
+
+/// Turns panics in the rest of the chain into
+/// `DispatchError::Panicked`, so a panicking handler fails its own
+/// dispatch instead of unwinding through the reactor.
+///
+/// Panics are only caught once this interceptor's `before` has run,
+/// so it should come first in the chain.
+pub struct CatchPanic<E>(PhantomData<E>);
+
+impl<E> CatchPanic<E> {
+    pub fn new() -> CatchPanic<E> {
+        CatchPanic(PhantomData)
+    }
+}
+
+impl<E> Default for CatchPanic<E> {
+    fn default() -> CatchPanic<E> {
+        CatchPanic::new()
+    }
+}
+
+impl<E: 'static> Interceptor for CatchPanic<E> {
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        context.catch_panics = true;
+        Box::new(future::ok(context))
+    }
+}
+
+/// Extract the message from a panic payload, which is a `&str` or a
+/// `String` for panics raised with `panic!`.
+pub fn message(payload: Box<Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "Box<Any>".to_string(),
+        },
+    }
+}
+
+/// Call `f`, converting a panic into `DispatchError::Panicked` when
+/// `enabled` is set.
+pub fn guard<T, F>(enabled: bool, event: &'static str, f: F) -> Result<T, DispatchError>
+where F: FnOnce() -> T,
+{
+    if !enabled {
+        return Ok(f());
+    }
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = message(payload);
+        error!("dispatch of {} panicked: {}", event, message);
+        DispatchError::Panicked { event, message }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use {Event,EventDispatcher};
+
+    struct Explode;
+
+    impl Event<DispatchError> for Explode {
+        fn handle(self: Box<Self>, _context: Context<DispatchError>) -> Box<Future<Item = Context<DispatchError>,
+                                                                                     Error = DispatchError>> {
+            panic!("boom")
+        }
+    }
+
+    struct Ping;
+
+    impl Event<DispatchError> for Ping {
+        fn handle(self: Box<Self>, context: Context<DispatchError>) -> Box<Future<Item = Context<DispatchError>,
+                                                                                    Error = DispatchError>> {
+            Box::new(future::ok(context))
+        }
+    }
+
+    #[test]
+    fn test_catch_panic_fails_dispatch() {
+        let dispatcher = EventDispatcher::new();
+        dispatcher.register_event::<Explode>(vec![Box::new(CatchPanic::new())]).unwrap();
+        dispatcher.register_event::<Ping>(vec![Box::new(CatchPanic::new())]).unwrap();
+
+        match dispatcher.dispatch(Explode).wait() {
+            Err(DispatchError::Panicked { event, message }) => {
+                assert!(event.ends_with("Explode"));
+                assert_eq!("boom", message);
+            },
+            _ => panic!("expected the dispatch to panic"),
+        }
+        assert!(dispatcher.dispatch(Ping).wait().is_ok());
+    }
+}
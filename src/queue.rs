@@ -13,7 +13,9 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::any::Any;
 use std::collections::VecDeque;
+use std::collections::vec_deque;
 use std::iter::{FromIterator,IntoIterator,Iterator};
 use std::rc::Rc;
 
@@ -21,7 +23,38 @@ use super::Interceptor;
 
 pub struct InterceptorQueue<E>(VecDeque<Rc<Box<Interceptor<Error = E>>>>);
 
+/// Returns true if the concrete type behind `interceptor` is `T`.
+fn is_a<T, E>(interceptor: &Rc<Box<Interceptor<Error = E>>>) -> bool
+where T: Interceptor<Error = E>,
+      E: 'static,
+{
+    let interceptor: &Interceptor<Error = E> = &***interceptor;
+    (interceptor as &Any).is::<T>()
+}
+
 impl<E> InterceptorQueue<E> {
+    pub fn new() -> InterceptorQueue<E> {
+        InterceptorQueue(VecDeque::new())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> vec_deque::Iter<'_, Rc<Box<Interceptor<Error = E>>>> {
+        self.0.iter()
+    }
+
+    pub fn push_front<T>(&mut self, value: T)
+    where T: Into<Rc<Box<Interceptor<Error = E>>>>,
+    {
+        self.0.push_front(value.into());
+    }
+
     pub fn push_back<T>(&mut self, value: T)
     where T: Into<Rc<Box<Interceptor<Error = E>>>>,
     {
@@ -32,11 +65,63 @@ impl<E> InterceptorQueue<E> {
         self.0.pop_front()
     }
 
+    pub fn pop_back(&mut self) -> Option<Rc<Box<Interceptor<Error = E>>>> {
+        self.0.pop_back()
+    }
+
+    /// Remove every interceptor for which `predicate` returns true,
+    /// preserving the order of the rest. Returns how many were
+    /// removed.
+    pub fn remove_where<P>(&mut self, mut predicate: P) -> usize
+    where P: FnMut(&Rc<Box<Interceptor<Error = E>>>) -> bool,
+    {
+        let before = self.0.len();
+        self.0.retain(|i| !predicate(i));
+        before - self.0.len()
+    }
+
     pub fn clear(&mut self) {
         self.0.clear()
     }
 }
 
+impl<E: 'static> InterceptorQueue<E> {
+    /// Returns the index of the first interceptor of type `T`.
+    pub fn position<T>(&self) -> Option<usize>
+    where T: Interceptor<Error = E>,
+    {
+        self.0.iter().position(is_a::<T, E>)
+    }
+
+    pub fn contains<T>(&self) -> bool
+    where T: Interceptor<Error = E>,
+    {
+        self.position::<T>().is_some()
+    }
+
+    /// Insert `value` immediately after the first interceptor of type
+    /// `T`. If there is no such interceptor, `value` is handed back.
+    pub fn insert_after<T>(&mut self, value: impl Into<Rc<Box<Interceptor<Error = E>>>>)
+                           -> Result<(), Rc<Box<Interceptor<Error = E>>>>
+    where T: Interceptor<Error = E>,
+    {
+        let value = value.into();
+        match self.position::<T>() {
+            Some(index) => {
+                self.0.insert(index + 1, value);
+                Ok(())
+            },
+            None => Err(value),
+        }
+    }
+}
+
+impl<E> Default for InterceptorQueue<E> {
+    fn default() -> InterceptorQueue<E> {
+        InterceptorQueue::new()
+    }
+}
+
 impl<E> FromIterator<Rc<Box<Interceptor<Error = E>>>> for InterceptorQueue<E> {
     fn from_iter<T>(iter: T) -> InterceptorQueue<E>
     where T: IntoIterator<Item = Rc<Box<Interceptor<Error = E>>>>
@@ -45,10 +130,97 @@ impl<E> FromIterator<Rc<Box<Interceptor<Error = E>>>> for InterceptorQueue<E> {
     }
 }
 
+impl<E> IntoIterator for InterceptorQueue<E> {
+    type Item = Rc<Box<Interceptor<Error = E>>>;
+    type IntoIter = vec_deque::IntoIter<Rc<Box<Interceptor<Error = E>>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a, E> IntoIterator for &'a InterceptorQueue<E> {
+    type Item = &'a Rc<Box<Interceptor<Error = E>>>;
+    type IntoIter = vec_deque::Iter<'a, Rc<Box<Interceptor<Error = E>>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
 impl<E> Extend<Box<Interceptor<Error = E>>> for InterceptorQueue<E> {
     fn extend<T>(&mut self, iter: T)
     where T: IntoIterator<Item = Box<Interceptor<Error = E>>>
     {
-        self.0.extend(iter.into_iter().map(|i| Rc::new(i)))
+        self.0.extend(iter.into_iter().map(Rc::new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct First;
+    impl Interceptor for First {
+        type Error = ();
+    }
+
+    struct Second;
+    impl Interceptor for Second {
+        type Error = ();
+    }
+
+    struct Third;
+    impl Interceptor for Third {
+        type Error = ();
+    }
+
+    fn boxed<I: Interceptor<Error = ()> + 'static>(i: I) -> Box<Interceptor<Error = ()>> {
+        Box::new(i)
+    }
+
+    #[test]
+    fn test_push_front() {
+        let mut queue = InterceptorQueue::new();
+        queue.push_back(boxed(Second));
+        queue.push_front(boxed(First));
+
+        assert_eq!(2, queue.len());
+        assert_eq!(Some(0), queue.position::<First>());
+        assert_eq!(Some(1), queue.position::<Second>());
+    }
+
+    #[test]
+    fn test_insert_after() {
+        let mut queue = InterceptorQueue::new();
+        queue.push_back(boxed(First));
+        queue.push_back(boxed(Third));
+
+        assert!(queue.insert_after::<First>(boxed(Second)).is_ok());
+        assert_eq!(Some(1), queue.position::<Second>());
+        assert_eq!(Some(2), queue.position::<Third>());
+    }
+
+    #[test]
+    fn test_insert_after_missing_returns_value() {
+        let mut queue: InterceptorQueue<()> = InterceptorQueue::new();
+        queue.push_back(boxed(First));
+
+        assert!(queue.insert_after::<Second>(boxed(Third)).is_err());
+        assert_eq!(1, queue.len());
+        assert!(!queue.contains::<Third>());
+    }
+
+    #[test]
+    fn test_remove_where() {
+        let mut queue = InterceptorQueue::new();
+        queue.push_back(boxed(First));
+        queue.push_back(boxed(Second));
+        queue.push_back(boxed(First));
+
+        assert_eq!(2, queue.remove_where(is_a::<First, ()>));
+        assert_eq!(1, queue.len());
+        assert!(queue.contains::<Second>());
+        assert!(!queue.is_empty());
     }
 }
@@ -0,0 +1,333 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::io::{self,BufRead};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::thread;
+
+use futures::{Async,Future,Poll,Sink,Stream};
+use futures::sync::mpsc;
+use futures::task::{self,Task};
+use futures::unsync::oneshot;
+
+use super::{DispatchError,EventDispatcher,Lifecycle};
+
+/// What an attached source does when its stream yields an error or
+/// one of its dispatches fails.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum ErrorPolicy {
+    /// Log the error and carry on with the next item.
+    Skip,
+    /// Log the error and detach the source.
+    Detach,
+}
+
+struct SourceState {
+    attached: bool,
+    waiting: Vec<oneshot::Sender<()>>,
+    /// The task driving the source, while it waits on its stream.
+    task: Option<Task>,
+}
+
+/// A handle to a stream attached to an `App`. Dropping the handle
+/// does not detach the source.
+#[derive(Clone)]
+pub struct SourceHandle(Rc<RefCell<SourceState>>);
+
+impl SourceHandle {
+    fn new() -> SourceHandle {
+        SourceHandle(Rc::new(RefCell::new(SourceState { attached: true, waiting: vec![], task: None })))
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.0.borrow().attached
+    }
+
+    /// Detach the source. Any dispatch already in flight completes,
+    /// but no more items are taken from the stream, which is dropped
+    /// even if it is waiting for its next item.
+    pub fn detach(&self) {
+        let (waiting, task) = {
+            let mut state = self.0.borrow_mut();
+            state.attached = false;
+            (state.waiting.split_off(0), state.task.take())
+        };
+        for waiter in waiting {
+            let _ = waiter.send(());
+        }
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+
+    /// Wake the current task when the source is detached.
+    fn park(&self) {
+        self.0.borrow_mut().task = Some(task::current());
+    }
+
+    /// A future that resolves once the source has been detached,
+    /// either explicitly or because its stream ended.
+    pub fn detached(&self) -> Box<Future<Item = (), Error = ()>> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.0.borrow_mut();
+        if state.attached {
+            state.waiting.push(tx);
+        } else {
+            let _ = tx.send(());
+        }
+        Box::new(rx.map_err(|_| ()))
+    }
+}
+
+/// Drives a stream into dispatches, one item at a time: the next item
+/// is not taken from the stream until the previous dispatch has
+/// completed. The source detaches itself once its `Lifecycle` starts
+/// shutting down.
+pub struct Attached<S, F, Ev, E> {
+    stream: S,
+    mapper: F,
+    policy: ErrorPolicy,
+    dispatcher: EventDispatcher<E>,
+    lifecycle: Lifecycle,
+    in_flight: Option<Box<Future<Item = (), Error = E>>>,
+    handle: SourceHandle,
+    phantom: PhantomData<Ev>,
+}
+
+impl<S, F, Ev, E> Attached<S, F, Ev, E> {
+    pub fn new(stream: S, mapper: F, policy: ErrorPolicy,
+               dispatcher: &EventDispatcher<E>,
+               lifecycle: &Lifecycle) -> Attached<S, F, Ev, E> {
+        Attached {
+            stream,
+            mapper,
+            policy,
+            dispatcher: dispatcher.clone(),
+            lifecycle: lifecycle.clone(),
+            in_flight: None,
+            handle: SourceHandle::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn handle(&self) -> SourceHandle {
+        self.handle.clone()
+    }
+
+    fn finish(&self) -> Poll<(), ()> {
+        self.handle.detach();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S, F, Ev, E> Future for Attached<S, F, Ev, E>
+where S: Stream,
+      S::Error: Debug,
+      F: FnMut(S::Item) -> Ev,
+      Ev: 'static,
+      E: 'static + Debug + From<DispatchError>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if let Some(mut in_flight) = self.in_flight.take() {
+                match in_flight.poll() {
+                    Ok(Async::NotReady) => {
+                        self.in_flight = Some(in_flight);
+                        return Ok(Async::NotReady);
+                    },
+                    Ok(Async::Ready(())) => {},
+                    Err(e) => {
+                        warn!("dispatch from attached source failed: {:?}", e);
+                        if self.policy == ErrorPolicy::Detach {
+                            return self.finish();
+                        }
+                    },
+                }
+            }
+            if !self.handle.is_attached() || self.lifecycle.is_stopping() {
+                return self.finish();
+            }
+            match self.stream.poll() {
+                Ok(Async::Ready(Some(item))) => {
+                    let event = (self.mapper)(item);
+                    let dispatched = self.dispatcher.dispatch(event).map(|_| ());
+                    self.in_flight = Some(Box::new(self.lifecycle.track(dispatched)));
+                },
+                Ok(Async::Ready(None)) => return self.finish(),
+                Ok(Async::NotReady) => {
+                    self.handle.park();
+                    self.lifecycle.park();
+                    return Ok(Async::NotReady);
+                },
+                Err(e) => {
+                    warn!("attached source failed: {:?}", e);
+                    if self.policy == ErrorPolicy::Detach {
+                        return self.finish();
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// A stream of lines read from stdin on a background thread. The
+/// reading thread blocks until the previous line has been taken, so
+/// stdin is only read as fast as lines are dispatched.
+pub struct StdinLines(mpsc::Receiver<io::Result<String>>);
+
+pub fn stdin_lines() -> StdinLines {
+    let (tx, rx) = mpsc::channel(0);
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut tx = tx;
+        for line in stdin.lock().lines() {
+            tx = match tx.send(line).wait() {
+                Ok(tx) => tx,
+                Err(_) => break,
+            };
+        }
+    });
+
+    StdinLines(rx)
+}
+
+impl Stream for StdinLines {
+    type Item = String;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, io::Error> {
+        match self.0.poll() {
+            Ok(Async::Ready(Some(Ok(line)))) => Ok(Async::Ready(Some(line))),
+            Ok(Async::Ready(Some(Err(e)))) => Err(e),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    use futures::future;
+    use futures::stream;
+    use tokio_core::reactor::Core;
+
+    use {App,Context,Dispatcher,Event};
+
+    struct Add(u32, Rc<Cell<u32>>);
+
+    impl Event<()> for Add {
+        fn handle(self: Box<Self>, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            self.1.set(self.1.get() + self.0);
+            Box::new(future::ok(context))
+        }
+    }
+
+    #[test]
+    fn test_source_dispatches_every_item() {
+        let mut core = Core::new().unwrap();
+        let app: App<()> = App::new(core.handle());
+        app.register_event::<Add>().unwrap();
+
+        let total = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&total);
+        let source = app.attach_source(stream::iter_ok::<_, ()>(vec![1, 2, 3]),
+                                       move |n| Add(n, Rc::clone(&counter)));
+
+        core.run(source.detached()).unwrap();
+        assert_eq!(6, total.get());
+        assert!(!source.is_attached());
+    }
+
+    #[test]
+    fn test_detach_policy_stops_at_error() {
+        let mut core = Core::new().unwrap();
+        let app: App<()> = App::new(core.handle());
+        app.register_event::<Add>().unwrap();
+
+        let total = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&total);
+        let items = vec![Ok(1), Err("bad input"), Ok(2)];
+        let source = app.attach_source_with(stream::iter_result(items),
+                                            move |n| Add(n, Rc::clone(&counter)),
+                                            ErrorPolicy::Detach);
+
+        core.run(source.detached()).unwrap();
+        assert_eq!(1, total.get());
+    }
+
+    /// A stream that never yields, and records being dropped.
+    struct Pending(Rc<Cell<bool>>);
+
+    impl Stream for Pending {
+        type Item = u32;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Option<u32>, ()> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl Drop for Pending {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn test_detach_drops_a_waiting_stream() {
+        let mut core = Core::new().unwrap();
+        let app: App<()> = App::new(core.handle());
+        app.register_event::<Add>().unwrap();
+
+        let dropped = Rc::new(Cell::new(false));
+        let total = Rc::new(Cell::new(0));
+        let source = app.attach_source(Pending(Rc::clone(&dropped)), move |n| Add(n, Rc::clone(&total)));
+        core.turn(Some(::std::time::Duration::from_millis(0)));
+        assert!(!dropped.get());
+
+        source.detach();
+        core.turn(Some(::std::time::Duration::from_millis(0)));
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn test_shutdown_drops_a_waiting_stream() {
+        let mut core = Core::new().unwrap();
+        let dispatcher: EventDispatcher<()> = EventDispatcher::new();
+        dispatcher.register_event::<Add>(vec![]).unwrap();
+
+        let dropped = Rc::new(Cell::new(false));
+        let total = Rc::new(Cell::new(0));
+        core.handle().spawn(Attached::new(Pending(Rc::clone(&dropped)), move |n| Add(n, Rc::clone(&total)),
+                                          ErrorPolicy::Skip, &dispatcher, dispatcher.lifecycle()));
+        core.turn(Some(::std::time::Duration::from_millis(0)));
+        assert!(!dropped.get());
+
+        Dispatcher::new(&core.handle(), &dispatcher).shutdown(0).action();
+        core.turn(Some(::std::time::Duration::from_millis(0)));
+        assert!(dropped.get());
+    }
+}
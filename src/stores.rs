@@ -0,0 +1,333 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::any::{Any,TypeId,type_name};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use futures::{future,Future};
+
+use super::{Context,Db,DispatchError,HandleEffects,InjectCoeffect,Interceptor};
+
+/// Identifies a store by the type of its state.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub struct StoreId {
+    id: TypeId,
+    name: &'static str,
+}
+
+impl StoreId {
+    pub fn of<S: 'static>() -> StoreId {
+        StoreId { id: TypeId::of::<S>(), name: type_name::<S>() }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// The stores an event reads and mutates, declared for it with
+/// `AppBuilder::access`.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Access {
+    reads: Vec<StoreId>,
+    writes: Vec<StoreId>,
+}
+
+impl Access {
+    pub fn new() -> Access {
+        Access::default()
+    }
+
+    pub fn reads<S: 'static>(mut self) -> Access {
+        self.reads.push(StoreId::of::<S>());
+        self
+    }
+
+    pub fn writes<S: 'static>(mut self) -> Access {
+        self.writes.push(StoreId::of::<S>());
+        self
+    }
+
+    /// Every store named, mutated ones first.
+    pub fn stores(&self) -> Vec<StoreId> {
+        let mut stores = self.writes.clone();
+        stores.extend(self.reads.iter().filter(|id| !self.writes.contains(id)));
+        stores
+    }
+
+    /// A store that keeps dispatches with these accesses from
+    /// overlapping, because one mutates what the other reads or
+    /// mutates.
+    pub fn conflict(&self, other: &Access) -> Option<StoreId> {
+        self.writes.iter().find(|id| other.writes.contains(id) || other.reads.contains(id))
+            .or_else(|| self.reads.iter().find(|id| other.writes.contains(id)))
+            .cloned()
+    }
+}
+
+/// A `Db` held by an `App`, whatever its state type.
+pub trait Store {
+    fn id(&self) -> StoreId;
+
+    fn as_any(&self) -> &Any;
+
+    /// An interceptor that injects the `Db` as a coeffect.
+    fn inject(&self) -> Box<Interceptor<Error = ()>>;
+
+    /// Have `handle_effects` restore the `Db` if a transaction fails.
+    fn stage(&self, handle_effects: HandleEffects<()>) -> HandleEffects<()>;
+}
+
+impl<S: 'static + Clone> Store for Db<S> {
+    fn id(&self) -> StoreId {
+        StoreId::of::<S>()
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn inject(&self) -> Box<Interceptor<Error = ()>> {
+        Box::new(InjectCoeffect::<Db<S>, ()>::new(self.clone()))
+    }
+
+    fn stage(&self, handle_effects: HandleEffects<()>) -> HandleEffects<()> {
+        handle_effects.stage(self)
+    }
+}
+
+struct InFlight {
+    next: u64,
+    dispatches: Vec<(u64, &'static str, Access)>,
+}
+
+/// The accesses of the dispatches currently holding stores.
+#[derive(Clone)]
+pub struct StoreLocks(Rc<RefCell<InFlight>>);
+
+impl Default for StoreLocks {
+    fn default() -> StoreLocks {
+        StoreLocks(Rc::new(RefCell::new(InFlight { next: 0, dispatches: vec![] })))
+    }
+}
+
+impl StoreLocks {
+    pub fn new() -> StoreLocks {
+        StoreLocks::default()
+    }
+
+    /// Hold the stores of `access` for a dispatch of `event`, unless a
+    /// dispatch in flight holds them in a conflicting way.
+    pub fn acquire(&self, event: &'static str, access: &Access) -> Result<Lease, DispatchError> {
+        let mut in_flight = self.0.borrow_mut();
+        let conflict = in_flight.dispatches.iter()
+            .filter_map(|&(_, with, ref held)| access.conflict(held).map(|store| (with, store)))
+            .next();
+        if let Some((with, store)) = conflict {
+            return Err(DispatchError::Conflict { event, store: store.name(), with });
+        }
+        let id = in_flight.next;
+        in_flight.next += 1;
+        in_flight.dispatches.push((id, event, access.clone()));
+        Ok(Lease { locks: self.clone(), id })
+    }
+}
+
+/// Stores held by one dispatch, released when dropped.
+pub struct Lease {
+    locks: StoreLocks,
+    id: u64,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.locks.0.borrow_mut().dispatches.retain(|&(held, _, _)| held != id);
+    }
+}
+
+/// Fails a dispatch with `DispatchError::Conflict` if another one in
+/// flight holds its stores in a conflicting way, and otherwise holds
+/// them until its own `after`. Dispatches of events with no declared
+/// access are never checked.
+pub struct StoreGuard<E> {
+    locks: StoreLocks,
+    access: Access,
+    phantom: PhantomData<E>,
+}
+
+impl<E> StoreGuard<E> {
+    pub fn new(locks: &StoreLocks, access: Access) -> StoreGuard<E> {
+        StoreGuard { locks: locks.clone(), access, phantom: PhantomData }
+    }
+}
+
+impl<E> Interceptor for StoreGuard<E>
+where E: 'static + From<DispatchError>,
+{
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        let event = context.event_name().unwrap_or("unknown");
+        match self.locks.acquire(event, &self.access) {
+            Ok(lease) => {
+                context.coeffects.insert(lease);
+                Box::new(future::ok(context))
+            },
+            Err(error) => {
+                warn!("{}", error);
+                Box::new(future::err(context.fail(error)))
+            },
+        }
+    }
+
+    fn after(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        context.coeffects.remove::<Lease>();
+        Box::new(future::ok(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{Async,Poll};
+    use futures::sync::oneshot;
+    use tokio_core::reactor::Core;
+
+    use {App,Event,RegistrationError};
+
+    #[derive(Clone,Debug,Default,PartialEq)]
+    struct Session(Option<String>);
+
+    #[derive(Clone,Debug,Default,PartialEq)]
+    struct Document(Vec<String>);
+
+    struct Login(&'static str);
+    struct Edit(&'static str);
+    struct Save(oneshot::Receiver<()>);
+    struct Whoami;
+
+    impl Event<()> for Whoami {
+        fn handle(self: Box<Self>, context: Context<()>) -> Box<Future<Item = Context<()>, Error = ()>> {
+            Box::new(future::ok(context))
+        }
+    }
+
+    fn app(core: &Core) -> App<()> {
+        let app: App<()> = App::builder(core.handle())
+            .store(Session::default())
+            .store(Document::default())
+            .access::<Login>(Access::new().writes::<Session>())
+            .access::<Edit>(Access::new().reads::<Session>().writes::<Document>())
+            .access::<Save>(Access::new().reads::<Document>())
+            .access::<Whoami>(Access::new().reads::<Session>())
+            .build();
+        app.register_fn(|Login(user), mut context: Context<()>| {
+            let session = context.coeffects.remove::<Db<Session>>().unwrap();
+            context.push_effect(session.mutate(move |s: &mut Session| s.0 = Some(user.to_string())));
+            context
+        }).unwrap();
+        app.register_fn(|Edit(line), mut context: Context<()>| {
+            let session = context.coeffects.remove::<Db<Session>>().unwrap();
+            let document = context.coeffects.remove::<Db<Document>>().unwrap();
+            let line = format!("{}: {}", session.borrow().0.clone().unwrap_or_default(), line);
+            context.push_effect(document.mutate(move |d: &mut Document| d.0.push(line)));
+            context
+        }).unwrap();
+        app.register_fn(|Save(saved), context: Context<()>| saved.then(|_| Ok(context))).unwrap();
+        app.register_fn(|Whoami, context: Context<()>| context).unwrap();
+        app
+    }
+
+    fn pending<F: Future>(future: &mut F) -> Poll<bool, ()> {
+        Ok(Async::Ready(matches!(future.poll(), Ok(Async::NotReady))))
+    }
+
+    #[test]
+    fn test_stores_are_injected_by_type() {
+        let mut core = Core::new().unwrap();
+        let app = app(&core);
+
+        core.run(app.dispatch(Login("ann"))).ok().unwrap();
+        core.run(app.dispatch(Edit("hello"))).ok().unwrap();
+
+        assert_eq!(Session(Some("ann".to_string())), *app.store::<Session>().unwrap().borrow());
+        assert_eq!(Document(vec!["ann: hello".to_string()]), *app.store::<Document>().unwrap().borrow());
+        assert!(app.store::<()>().is_some());
+        assert!(app.store::<u32>().is_none());
+    }
+
+    #[test]
+    fn test_only_declared_stores_are_injected() {
+        let mut core = Core::new().unwrap();
+        let app = app(&core);
+        struct Undeclared;
+        app.register_fn(|Undeclared, context: Context<()>| context).unwrap();
+
+        let whoami = core.run(app.dispatch_dry_run(Whoami)).ok().unwrap();
+        assert!(whoami.coeffects.contains::<Db<Session>>());
+        assert!(!whoami.coeffects.contains::<Db<Document>>());
+        assert!(!whoami.coeffects.contains::<Db<()>>());
+        let undeclared = core.run(app.dispatch_dry_run(Undeclared)).ok().unwrap();
+        assert!(undeclared.coeffects.contains::<Db<Session>>());
+        assert!(undeclared.coeffects.contains::<Db<Document>>());
+        assert!(undeclared.coeffects.contains::<Db<()>>());
+    }
+
+    #[test]
+    fn test_conflicting_dispatches_fail() {
+        let mut core = Core::new().unwrap();
+        let app = app(&core);
+        let (tx, rx) = oneshot::channel();
+
+        let mut save = app.dispatch(Save(rx));
+        assert!(core.run(future::poll_fn(|| pending(&mut save))).unwrap());
+        assert!(core.run(app.dispatch(Edit("too soon"))).is_err());
+        assert!(core.run(app.dispatch(Login("bob"))).is_ok());
+        assert!(core.run(app.dispatch(Whoami)).is_ok());
+
+        tx.send(()).unwrap();
+        assert!(core.run(save).is_ok());
+        assert!(core.run(app.dispatch(Edit("later"))).is_ok());
+        assert_eq!(Document(vec!["bob: later".to_string()]), *app.store::<Document>().unwrap().borrow());
+    }
+
+    #[test]
+    fn test_access_to_unknown_store_is_rejected() {
+        let core = Core::new().unwrap();
+        let app: App<()> = App::builder(core.handle())
+            .access::<Whoami>(Access::new().reads::<Session>())
+            .build();
+
+        let unknown = RegistrationError::UnknownStore { event: type_name::<Whoami>(),
+                                                        store: type_name::<Session>() };
+        assert_eq!(Err(unknown.clone()), app.register_fn(|Whoami, context: Context<()>| context));
+        assert_eq!(Err(unknown), app.replace_event_with::<Whoami>(vec![]).map(|replaced| replaced.is_some()));
+    }
+
+    #[test]
+    fn test_access_conflicts() {
+        let reader = Access::new().reads::<Session>();
+        let writer = Access::new().reads::<Document>().writes::<Session>();
+
+        assert_eq!(None, reader.conflict(&reader));
+        assert_eq!(Some(StoreId::of::<Session>()), reader.conflict(&writer));
+        assert_eq!(Some(StoreId::of::<Session>()), writer.conflict(&reader));
+        assert_eq!(vec![StoreId::of::<Session>(), StoreId::of::<Document>()], writer.stores());
+    }
+}
@@ -0,0 +1,153 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::marker::PhantomData;
+use std::time::{Duration,Instant};
+
+use futures::{future,Async,Future};
+use tokio_core::reactor::{self,Handle};
+
+use super::{Context,Interceptor};
+
+/// A point in time after which the dispatch carrying it is aborted.
+pub struct Deadline {
+    at: Instant,
+    after: Duration,
+    timer: reactor::Timeout,
+}
+
+impl Deadline {
+    pub fn after(&self) -> Duration {
+        self.after
+    }
+
+    /// Keep whichever of the two deadlines expires first.
+    pub fn earliest(self, other: Deadline) -> Deadline {
+        if other.at < self.at { other } else { self }
+    }
+
+    /// Returns true once the deadline has passed. A timer that fails
+    /// is treated as never expiring.
+    pub fn poll_expired(&mut self) -> bool {
+        match self.timer.poll() {
+            Ok(Async::Ready(())) => true,
+            Ok(Async::NotReady) => false,
+            Err(e) => {
+                warn!("deadline timer failed, ignoring deadline: {}", e);
+                false
+            },
+        }
+    }
+}
+
+/// Aborts the rest of the dispatch if it has not completed within
+/// `duration` of this interceptor's `before` being called.
+///
+/// Interceptors that have already been entered get their `error`
+/// called with `DispatchError::TimedOut`, exactly as if the chain
+/// had failed on its own.
+pub struct Timeout<E> {
+    handle: Handle,
+    duration: Duration,
+    phantom: PhantomData<E>,
+}
+
+impl<E> Timeout<E> {
+    pub fn new(handle: &Handle, duration: Duration) -> Timeout<E> {
+        Timeout { handle: handle.clone(), duration, phantom: PhantomData }
+    }
+}
+
+impl<E: 'static> Interceptor for Timeout<E> {
+    type Error = E;
+
+    fn before(&self, mut context: Context<E>) -> Box<Future<Item = Context<E>, Error = E>> {
+        match reactor::Timeout::new(self.duration, &self.handle) {
+            Ok(timer) => {
+                let deadline = Deadline {
+                    at: Instant::now() + self.duration,
+                    after: self.duration,
+                    timer,
+                };
+                context.deadline = Some(match context.deadline.take() {
+                    Some(existing) => existing.earliest(deadline),
+                    None => deadline,
+                });
+            },
+            Err(e) => warn!("failed to create timer for {:?} timeout: {}", self.duration, e),
+        };
+        Box::new(future::ok(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use tokio_core::reactor::Core;
+
+    use {DispatchError,Event,EventDispatcher};
+
+    struct Stall;
+
+    impl Interceptor for Stall {
+        type Error = DispatchError;
+
+        fn before(&self, _context: Context<DispatchError>) -> Box<Future<Item = Context<DispatchError>,
+                                                                        Error = DispatchError>> {
+            Box::new(future::empty())
+        }
+    }
+
+    struct RecordError(Rc<Cell<bool>>);
+
+    impl Interceptor for RecordError {
+        type Error = DispatchError;
+
+        fn error(&self, _error: &DispatchError) {
+            self.0.set(true);
+        }
+    }
+
+    struct Never;
+
+    impl Event<DispatchError> for Never {
+        fn handle(self: Box<Self>, context: Context<DispatchError>) -> Box<Future<Item = Context<DispatchError>,
+                                                                                    Error = DispatchError>> {
+            Box::new(future::ok(context))
+        }
+    }
+
+    #[test]
+    fn test_timeout_aborts_stalled_chain() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let errored = Rc::new(Cell::new(false));
+
+        let dispatcher = EventDispatcher::new();
+        dispatcher.register_event::<Never>(vec![Box::new(RecordError(Rc::clone(&errored))),
+                                                Box::new(Timeout::new(&handle, Duration::from_millis(10))),
+                                                Box::new(Stall)]).unwrap();
+
+        match core.run(dispatcher.dispatch(Never)) {
+            Err(DispatchError::TimedOut { after, .. }) => assert_eq!(Duration::from_millis(10), after),
+            _ => panic!("expected the dispatch to time out"),
+        }
+        assert!(errored.get());
+    }
+}
@@ -0,0 +1,186 @@
+// This file is part of tokio-interceptor.
+//
+// tokio-interceptor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// tokio-interceptor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with tokio-interceptor.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Attribute macros for `tokio-interceptor`.
+//!
+//! `#[event]` turns a plain function into an event handler:
+//!
+//! ```ignore
+//! #[event]
+//! fn add_todo(db: &Db<AppState>, input: NonEmptyInput) -> Effects {
+//!     vec![Box::new(db.mutate(move |state: &mut AppState| state.todos.push((false, input.0))))]
+//! }
+//!
+//! app.register_event::<AddTodo>()?;
+//! ```
+//!
+//! The function is kept as written, and a unit struct named after it
+//! in CamelCase is generated along with its `Event` impl. Each argument
+//! is a coeffect looked up in the context by type: `&T` borrows it and
+//! `T` takes it out of the context. If a coeffect is missing, the
+//! dispatch fails with `DispatchError::MissingCoeffect`. The returned
+//! effects, anything iterable over `Box<Effect>`, are pushed onto the
+//! context; a function returning nothing pushes none.
+//!
+//! `#[event(Quit)]` implements `Event` for an existing type `Quit`
+//! instead, and an argument of type `Quit` receives the event itself.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span,TokenStream as TokenStream2};
+use syn::{FnArg,Ident,ItemFn,Path,ReturnType,Type};
+use syn::spanned::Spanned;
+
+#[proc_macro_attribute]
+pub fn event(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let function = syn::parse_macro_input!(item as ItemFn);
+    let event = if attr.is_empty() {
+        None
+    } else {
+        Some(syn::parse_macro_input!(attr as Path))
+    };
+    match expand(event, function) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap();
+            first.to_uppercase().chain(chars).collect::<String>()
+        })
+        .collect()
+}
+
+fn is_event_type(ty: &Type, event: &Option<Path>) -> bool {
+    match (ty, event) {
+        (Type::Path(ty), Some(event)) =>
+            ty.qself.is_none() && ty.path.segments.last().map(|segment| &segment.ident) ==
+                event.segments.last().map(|segment| &segment.ident),
+        _ => false,
+    }
+}
+
+fn expand(event: Option<Path>, function: ItemFn) -> syn::Result<TokenStream2> {
+    let sig = &function.sig;
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new(sig.generics.span(), "#[event] functions cannot be generic"));
+    }
+    if let Some(ref asyncness) = sig.asyncness {
+        return Err(syn::Error::new(asyncness.span(), "#[event] functions cannot be async"));
+    }
+
+    let name = &sig.ident;
+    let vis = &function.vis;
+    let (event_type, definition) = match event {
+        Some(ref path) => (quote!(#path), quote!()),
+        None => {
+            let ident = Ident::new(&camel_case(&name.to_string()), name.span());
+            (quote!(#ident), quote!(#vis struct #ident;))
+        },
+    };
+
+    // Every coeffect is checked for before any is taken, so that a
+    // failed dispatch leaves the context as it found it. Coeffects
+    // taken by value are removed first, so that the borrows of the
+    // rest can be held across the call.
+    let mut checks = vec![];
+    let mut takes = vec![];
+    let mut borrows = vec![];
+    let mut args = vec![];
+    for (i, input) in sig.inputs.iter().enumerate() {
+        let ty = match *input {
+            FnArg::Typed(ref pat) => &*pat.ty,
+            FnArg::Receiver(ref receiver) =>
+                return Err(syn::Error::new(receiver.span(), "#[event] functions cannot take self")),
+        };
+        let arg = Ident::new(&format!("__coeffect{}", i), Span::call_site());
+        match *ty {
+            Type::Reference(ref reference) => {
+                if let Some(ref mutability) = reference.mutability {
+                    return Err(syn::Error::new(mutability.span(),
+                                               "coeffects can only be borrowed immutably"));
+                }
+                let ty = &reference.elem;
+                checks.push(quote! {
+                    if !context.coeffects.contains::<#ty>() {
+                        return ::tokio_interceptor::__private::missing_coeffect::<Self, #ty, E>(&context);
+                    }
+                });
+                borrows.push(quote! {
+                    let #arg = match context.coeffects.get::<#ty>() {
+                        Some(coeffect) => coeffect,
+                        None => return ::tokio_interceptor::__private::missing_coeffect::<Self, #ty, E>(&context),
+                    };
+                });
+            },
+            ref ty if is_event_type(ty, &event) => {
+                takes.push(quote!(let #arg = *self;));
+            },
+            ref ty => {
+                checks.push(quote! {
+                    if !context.coeffects.contains::<#ty>() {
+                        return ::tokio_interceptor::__private::missing_coeffect::<Self, #ty, E>(&context);
+                    }
+                });
+                takes.push(quote! {
+                    let #arg = match context.coeffects.remove::<#ty>() {
+                        Some(coeffect) => coeffect,
+                        None => return ::tokio_interceptor::__private::missing_coeffect::<Self, #ty, E>(&context),
+                    };
+                });
+            },
+        }
+        args.push(arg);
+    }
+
+    let push_effects = match sig.output {
+        ReturnType::Default => quote!(),
+        ReturnType::Type(..) => quote!(context.effects.extend(effects);),
+    };
+
+    Ok(quote! {
+        #function
+
+        #definition
+
+        impl<E> ::tokio_interceptor::Event<E> for #event_type
+        where E: 'static + ::std::convert::From<::tokio_interceptor::DispatchError>,
+        {
+            #[allow(unused_mut, unused_variables)]
+            fn handle(self: ::std::boxed::Box<Self>, mut context: ::tokio_interceptor::Context<E>)
+                      -> ::tokio_interceptor::__private::BoxFuture<E> {
+                #(#checks)*
+                #(#takes)*
+                #[allow(clippy::let_unit_value)]
+                let effects = {
+                    #(#borrows)*
+                    #name(#(#args),*)
+                };
+                #push_effects
+                ::tokio_interceptor::__private::done(context)
+            }
+        }
+    })
+}
@@ -0,0 +1,93 @@
+extern crate futures;
+extern crate tokio_interceptor;
+extern crate tokio_interceptor_macros;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use futures::Future;
+use tokio_interceptor::{Context, DispatchError, Effects, EventDispatcher, Interceptor,
+                        before_fn};
+use tokio_interceptor_macros::event;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Log(Rc<RefCell<Vec<String>>>);
+
+struct Name(&'static str);
+
+#[event]
+fn greet(log: &Log, name: Name) -> Effects {
+    log.0.borrow_mut().push(format!("hello {}", name.0));
+    vec![]
+}
+
+struct Repeat(usize);
+
+#[event(Repeat)]
+fn repeat(event: Repeat, log: &Log) {
+    let line = log.0.borrow()[0].clone();
+    for _ in 0..event.0 {
+        log.0.borrow_mut().push(line.clone());
+    }
+}
+
+mod words {
+    pub struct Shout(pub &'static str);
+}
+
+use self::words::Shout;
+
+#[event(words::Shout)]
+fn shout(event: Shout, log: &Log) {
+    log.0.borrow_mut().push(event.0.to_uppercase());
+}
+
+fn inject_log(log: &Log) -> Box<Interceptor<Error = DispatchError>> {
+    let log = log.clone();
+    Box::new(before_fn(move |mut context: Context<DispatchError>| {
+        context.coeffects.insert(log.clone());
+        context
+    }))
+}
+
+#[test]
+fn test_event_extracts_coeffects() {
+    let dispatcher = EventDispatcher::new();
+    let log = Log(Rc::new(RefCell::new(vec![])));
+    dispatcher.register_event::<Greet>(vec![
+        inject_log(&log),
+        Box::new(before_fn(|mut context: Context<DispatchError>| {
+            context.coeffects.insert(Name("world"));
+            context
+        })),
+    ]).unwrap();
+    dispatcher.register_event::<Repeat>(vec![inject_log(&log)]).unwrap();
+
+    let context = dispatcher.dispatch(Greet).wait().unwrap();
+    assert!(!context.coeffects.contains::<Name>());
+    dispatcher.dispatch(Repeat(2)).wait().unwrap();
+
+    assert_eq!(vec!["hello world"; 3], *log.0.borrow());
+}
+
+#[test]
+fn test_missing_coeffect_fails_dispatch() {
+    let dispatcher: EventDispatcher<DispatchError> = EventDispatcher::new();
+    let log = Log(Rc::new(RefCell::new(vec![])));
+    dispatcher.register_event::<Greet>(vec![inject_log(&log)]).unwrap();
+
+    match dispatcher.dispatch(Greet).wait() {
+        Err(DispatchError::MissingCoeffect { coeffect, .. }) => assert!(coeffect.ends_with("Name")),
+        _ => panic!("expected the dispatch to fail"),
+    }
+}
+
+#[test]
+fn test_event_type_matches_by_last_segment() {
+    let dispatcher: EventDispatcher<DispatchError> = EventDispatcher::new();
+    let log = Log(Rc::new(RefCell::new(vec![])));
+    dispatcher.register_event::<Shout>(vec![inject_log(&log)]).unwrap();
+
+    dispatcher.dispatch(Shout("hey")).wait().unwrap();
+    assert_eq!(vec!["HEY"], *log.0.borrow());
+}